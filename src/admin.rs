@@ -0,0 +1,856 @@
+//! the admin API, for the operators of the DNS server, served under `/admin` if at least one
+//! operator token is configured (`admin_tokens`, see the `config` module)
+//! every request must carry `Authorization: Bearer <token>`, otherwise it is answered with
+//! 401 Unauthorized and `{"error": "unauthorized", ...}`
+//! - GET /admin/nodes?page=<N>&per_page=<N>&address=<IP address or CIDR range>
+//!    - list every node of the network whatever its state, with its `state`, ordered by address,
+//!      a page at a time: `{"items": [...], "page": 1, "per_page": 50, "total": 120}`
+//!    - `page` starts at 1, `per_page` is 50 by default and at most 500
+//!    - `address` (optional) only lists the nodes with an address in the range
+//! - PATCH /admin/nodes/<IP address>/<port>
+//!    - edit the metadata of the node (`protocol_version`, `services`, `user_agent` and
+//!      `best_height`, an empty `user_agent` clears it) and its `state`, `inactive` to take it
+//!      out of the queries as a deregistration does, `active` to put an inactive node back
+//!      with a fresh TTL (whatever the subnet caps), the fields that are not set are kept
+//!    - the change is stored as a registration is, the node can overwrite its metadata
+//!      with its next registration
+//!    - return the edited node with its `state`, 404 Not Found with `node_not_found`, or
+//!      400 Bad Request with `invalid_body` if there is nothing to change
+//! - DELETE /admin/nodes/<IP address>/<port>
+//!    - remove the node right away, it is not kept as inactive, it can register again
+//!      unless it is banned
+//!    - return `{"nodes": [...]}`, the removed nodes, or 404 Not Found with `node_not_found`
+//! - GET /admin/bans
+//!    - list the bans in force: `{"bans": [...]}`
+//! - POST /admin/bans
+//!    - ban an IP address or a CIDR range (`address`) or an Ed25519 public key (`public_key`),
+//!      for a `reason` and, optionally, for `expires_in` seconds (otherwise until it is lifted)
+//!    - the banned nodes of every network are removed, and cannot register or send a heartbeat
+//!      while the ban is in force (403 Forbidden with `banned`)
+//!    - return 201 Created with `{"ban": {...}, "removed": <number of removed nodes>}`
+//! - DELETE /admin/bans/<id>
+//!    - lift a ban, return it, or 404 Not Found with `ban_not_found`
+//! - GET /admin/audit?page=<N>&per_page=<N>
+//!    - the last admin actions, newest first, paginated as the nodes, each with the operator
+//!      (the name of its token) and the address it came from, they are logged as well
+//!
+//! the node endpoints serve the default network, and every network under `/admin/<network>`,
+//! e.g. `/admin/testnet/nodes`, the bans apply to all the networks,
+//! they are stored in `<storage_dir>/bans.json` if the storage is enabled
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::future::{ready, Ready};
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use actix_web::{delete, dev, get, patch, post, web, FromRequest, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::api::{ApiError, NodesResponse};
+use crate::cidr::Cidr;
+use crate::client;
+use crate::config::Config;
+use crate::events::{EventLog, NodeEventKind};
+use crate::metrics::{Event, Metrics};
+use crate::network::{Network, NetworkState, Networks};
+use crate::node::{now, Node, NodeAddr, NodeState};
+use crate::validation;
+
+/// the file holding the bans, in the storage directory
+pub const BANS_FILE: &str = "bans.json";
+/// the minimum length of a token
+const MIN_TOKEN_LEN: usize = 16;
+/// the maximum length of the reason of a ban
+const MAX_REASON_LEN: usize = 256;
+const DEFAULT_PER_PAGE: usize = 50;
+const MAX_PER_PAGE: usize = 500;
+
+/// the token of an operator, set as `<operator>:<token>`
+/// the token is never printed, neither by `--check-config` nor in the logs
+#[derive(Clone, PartialEq, Eq)]
+pub struct AdminToken {
+    /// the name of the operator, recorded in the audit log
+    pub operator: String,
+    token: String,
+}
+
+impl FromStr for AdminToken {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (operator, token) = s
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| "an admin token must be <operator>:<token>".to_string())?;
+        let valid = !operator.is_empty()
+            && operator.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"_-.".contains(&byte));
+        if !valid {
+            return Err(format!("{:?} is not an operator name made of a-z, A-Z, 0-9, _, - and .", operator));
+        }
+        if token.len() < MIN_TOKEN_LEN || !token.bytes().all(|byte| byte.is_ascii_graphic()) {
+            return Err(format!(
+                "the token of {} must be at least {} printable characters",
+                operator, MIN_TOKEN_LEN
+            ));
+        }
+        Ok(AdminToken {
+            operator: operator.to_string(),
+            token: token.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:<redacted>", self.operator)
+    }
+}
+
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminToken").field("operator", &self.operator).finish_non_exhaustive()
+    }
+}
+
+/// the operators and the audit log, registered as app data
+pub struct Admin {
+    /// the SHA-256 hashes of the tokens, with the names of their operators,
+    /// hashed so that comparing them takes the same time whatever the token
+    tokens: Vec<([u8; 32], String)>,
+    trusted_proxies: Vec<Cidr>,
+    audit: AuditLog,
+}
+
+impl Admin {
+    pub fn new(config: &Config) -> Admin {
+        Admin {
+            tokens: config
+                .admin_tokens
+                .iter()
+                .map(|token| (Sha256::digest(&token.token).into(), token.operator.clone()))
+                .collect(),
+            trusted_proxies: config.trusted_proxies.clone(),
+            audit: AuditLog::new(config.admin_audit_capacity),
+        }
+    }
+
+    /// the operator holding `token`
+    fn operator(&self, token: &str) -> Option<&str> {
+        let hash: [u8; 32] = Sha256::digest(token).into();
+        let mut operator = None;
+        for (expected, name) in &self.tokens {
+            let diff = expected.iter().zip(&hash).fold(0, |diff, (a, b)| diff | (a ^ b));
+            if diff == 0 {
+                operator = Some(name.as_str());
+            }
+        }
+        operator
+    }
+}
+
+/// the operator making a request, authenticated by its token
+#[derive(Debug)]
+pub struct Operator {
+    name: String,
+    /// the address the request came from
+    client: Option<IpAddr>,
+}
+
+impl FromRequest for Operator {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Operator, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        let admin = match req.app_data::<web::Data<Admin>>() {
+            Some(admin) => admin,
+            None => {
+                let err = ApiError::Internal("the admin API is not configured".to_string());
+                return ready(Err(err.into()));
+            }
+        };
+        let token = req
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let client = client::client_ip(req, &admin.trusted_proxies);
+        match token.and_then(|token| admin.operator(token.trim())) {
+            Some(name) => ready(Ok(Operator { name: name.to_string(), client })),
+            None => {
+                tracing::warn!(client = ?client, "admin request without a valid token");
+                ready(Err(ApiError::Unauthorized.into()))
+            }
+        }
+    }
+}
+
+/// what an operator did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    EditNode,
+    RemoveNode,
+    Ban,
+    Unban,
+}
+
+/// an entry of the audit log
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub id: u64,
+    /// the UNIX timestamp (in seconds) of the action
+    pub time: u64,
+    pub operator: String,
+    /// the address the request came from
+    pub client: Option<IpAddr>,
+    pub action: AuditAction,
+    /// the node, the address range or the public key the action is about
+    pub target: String,
+    pub detail: String,
+}
+
+/// the last admin actions
+struct AuditLog {
+    capacity: usize,
+    /// oldest first, and the ID of the next entry
+    entries: Mutex<(VecDeque<AuditEntry>, u64)>,
+}
+
+impl AuditLog {
+    fn new(capacity: usize) -> AuditLog {
+        AuditLog {
+            capacity: capacity.max(1),
+            entries: Mutex::new((VecDeque::new(), 1)),
+        }
+    }
+
+    fn record(&self, operator: &Operator, action: AuditAction, target: String, detail: String) {
+        tracing::info!(
+            operator = %operator.name,
+            client = ?operator.client,
+            action = ?action,
+            target = %target,
+            detail = %detail,
+            "admin action"
+        );
+        let mut entries = self.entries.lock().unwrap();
+        let (log, next_id) = &mut *entries;
+        if log.len() == self.capacity {
+            log.pop_front();
+        }
+        log.push_back(AuditEntry {
+            id: *next_id,
+            time: now(),
+            operator: operator.name.clone(),
+            client: operator.client,
+            action,
+            target,
+            detail,
+        });
+        *next_id += 1;
+    }
+
+    /// the entries, newest first
+    fn list(&self) -> Vec<AuditEntry> {
+        self.entries.lock().unwrap().0.iter().rev().cloned().collect()
+    }
+}
+
+/// what a ban applies to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanTarget {
+    /// the nodes with an address in the range, a single address is a /32 (or /128)
+    Address(Cidr),
+    /// the nodes bound to (or signing with) the hex-encoded Ed25519 public key
+    PublicKey(String),
+}
+
+impl BanTarget {
+    /// whether a node at `addresses`, bound to `public_key` if any, is banned
+    fn matches(&self, addresses: &[IpAddr], public_key: Option<&str>) -> bool {
+        match self {
+            BanTarget::Address(range) => addresses.iter().any(|address| range.contains(*address)),
+            BanTarget::PublicKey(key) => {
+                public_key.is_some_and(|public_key| public_key.trim().eq_ignore_ascii_case(key))
+            }
+        }
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanTarget::Address(range) => write!(f, "{}", range),
+            BanTarget::PublicKey(key) => write!(f, "{}", key),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub id: u64,
+    /// `address` or `public_key`
+    #[serde(flatten)]
+    pub target: BanTarget,
+    pub reason: String,
+    /// the operator who banned
+    pub created_by: String,
+    /// the UNIX timestamp (in seconds) of the ban
+    pub created_at: u64,
+    /// the UNIX timestamp (in seconds) the ban expires at, `None` if it does not
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    fn in_force(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+
+    fn matches_node(&self, node: &Node) -> bool {
+        self.target.matches(&node.ip_addresses(), node.public_key.as_deref())
+    }
+}
+
+/// the bans and the ID of the next ban, as stored in `bans.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Bans {
+    bans: Vec<Ban>,
+    next_id: u64,
+}
+
+/// the bans, registered as app data and checked by `/register` and `/heartbeat`
+/// the expired bans are dropped on the next change
+#[derive(Default)]
+pub struct BanList {
+    /// where the bans are stored, `None` to keep them in memory only
+    path: Option<PathBuf>,
+    bans: Mutex<Bans>,
+}
+
+impl BanList {
+    /// load the bans stored at `path`, the changes are written there
+    pub fn open(path: &Path) -> io::Result<BanList> {
+        let bans = match File::open(path) {
+            Ok(file) => serde_json::from_reader(io::BufReader::new(file))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Bans::default(),
+            Err(err) => return Err(err),
+        };
+        Ok(BanList {
+            path: Some(path.to_path_buf()),
+            bans: Mutex::new(bans),
+        })
+    }
+
+    /// the bans in force at `now`
+    pub fn list(&self, now: u64) -> Vec<Ban> {
+        let bans = self.bans.lock().unwrap();
+        bans.bans.iter().filter(|ban| ban.in_force(now)).cloned().collect()
+    }
+
+    /// reject a node at `address` signing with `public_key` if it is banned
+    pub fn check(&self, address: &NodeAddr, public_key: Option<&str>, now: u64) -> Result<(), ApiError> {
+        let addresses: Vec<IpAddr> = address
+            .ipv4_address
+            .map(IpAddr::V4)
+            .into_iter()
+            .chain(address.ipv6_address.map(IpAddr::V6))
+            .collect();
+        let bans = self.bans.lock().unwrap();
+        match bans
+            .bans
+            .iter()
+            .find(|ban| ban.in_force(now) && ban.target.matches(&addresses, public_key))
+        {
+            Some(ban) => Err(ApiError::Banned(ban.reason.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// ban `target` and store the ban
+    pub fn add(
+        &self,
+        target: BanTarget,
+        reason: String,
+        expires_at: Option<u64>,
+        created_by: &str,
+        now: u64,
+    ) -> io::Result<Ban> {
+        let mut bans = self.bans.lock().unwrap();
+        let ban = Ban {
+            id: bans.next_id.max(1),
+            target,
+            reason,
+            created_by: created_by.to_string(),
+            created_at: now,
+            expires_at,
+        };
+        let mut updated = Bans {
+            bans: bans.bans.iter().filter(|ban| ban.in_force(now)).cloned().collect(),
+            next_id: ban.id + 1,
+        };
+        updated.bans.push(ban.clone());
+        self.save(&updated)?;
+        *bans = updated;
+        Ok(ban)
+    }
+
+    /// lift the ban `id` and store the change, return the ban if it was in force
+    pub fn remove(&self, id: u64, now: u64) -> io::Result<Option<Ban>> {
+        let mut bans = self.bans.lock().unwrap();
+        let removed = bans.bans.iter().find(|ban| ban.id == id && ban.in_force(now)).cloned();
+        if removed.is_none() {
+            return Ok(None);
+        }
+        let updated = Bans {
+            bans: bans.bans.iter().filter(|ban| ban.id != id && ban.in_force(now)).cloned().collect(),
+            next_id: bans.next_id,
+        };
+        self.save(&updated)?;
+        *bans = updated;
+        Ok(removed)
+    }
+
+    /// atomically replace the stored bans, as the snapshot of the nodes
+    fn save(&self, bans: &Bans) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        fs::create_dir_all(dir)?;
+        let tmp_path = path.with_extension("json.tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        serde_json::to_writer(&mut tmp, bans)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        File::open(dir)?.sync_all()
+    }
+}
+
+/// a page of a list
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// the number of the page, from 1
+    pub page: usize,
+    pub per_page: usize,
+    /// the number of items of the whole list
+    pub total: usize,
+}
+
+impl<T> Page<T> {
+    /// the page `page` (from 1) of `items`, `per_page` items per page
+    fn of(items: Vec<T>, page: Option<usize>, per_page: Option<usize>) -> Page<T> {
+        let page = page.unwrap_or(1).max(1);
+        let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+        let total = items.len();
+        let items = items
+            .into_iter()
+            .skip((page - 1).saturating_mul(per_page))
+            .take(per_page)
+            .collect();
+        Page { items, page, per_page, total }
+    }
+}
+
+/// a node of `GET /admin/nodes`, with its lifecycle state
+#[derive(Debug, Serialize)]
+struct ListedNode {
+    #[serde(flatten)]
+    node: Node,
+    state: NodeState,
+}
+
+/// the query string of `GET /admin/nodes`
+#[derive(Debug, Deserialize)]
+struct NodeListParams {
+    page: Option<usize>,
+    per_page: Option<usize>,
+    /// only the nodes with an address in this range
+    address: Option<String>,
+}
+
+/// the query string of `GET /admin/audit`
+#[derive(Debug, Deserialize)]
+struct AuditParams {
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+/// the state an operator puts a node in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum EditedState {
+    Active,
+    Inactive,
+}
+
+/// the body of `PATCH /admin/nodes/{address}/{port}`, only the fields that are set are changed
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NodeEdit {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocol_version: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    services: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    best_height: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<EditedState>,
+}
+
+impl NodeEdit {
+    /// whether the edit changes nothing
+    fn is_empty(&self) -> bool {
+        self.protocol_version.is_none()
+            && self.services.is_none()
+            && self.user_agent.is_none()
+            && self.best_height.is_none()
+            && self.state.is_none()
+    }
+}
+
+/// the body of `POST /admin/bans`
+#[derive(Debug, Deserialize)]
+struct BanRequest {
+    address: Option<String>,
+    public_key: Option<String>,
+    #[serde(default)]
+    reason: String,
+    /// how many seconds the ban lasts, until it is lifted if not set
+    expires_in: Option<u64>,
+}
+
+impl BanRequest {
+    /// what to ban
+    fn target(&self) -> Result<BanTarget, ApiError> {
+        match (self.address.as_deref(), self.public_key.as_deref()) {
+            (Some(address), None) => address
+                .parse()
+                .map(BanTarget::Address)
+                .map_err(|err| ApiError::invalid_field("address", err)),
+            (None, Some(public_key)) => {
+                let public_key = public_key.trim().to_ascii_lowercase();
+                if public_key.len() != 64 || !public_key.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                    return Err(ApiError::invalid_field(
+                        "public_key",
+                        "public_key must be 32 bytes hex-encoded".to_string(),
+                    ));
+                }
+                Ok(BanTarget::PublicKey(public_key))
+            }
+            _ => Err(ApiError::invalid_field(
+                "address",
+                "either address or public_key is required".to_string(),
+            )),
+        }
+    }
+}
+
+/// the response of `POST /admin/bans`
+#[derive(Debug, Serialize)]
+struct BanResponse {
+    ban: Ban,
+    /// the number of nodes removed by the ban, in all the networks
+    removed: usize,
+}
+
+/// the response of `GET /admin/bans`
+#[derive(Debug, Serialize)]
+struct BansResponse {
+    bans: Vec<Ban>,
+}
+
+/// remove the nodes of `network` accepted by `filter`, and tell the subscribers
+fn remove_nodes(
+    network: &NetworkState,
+    filter: &dyn Fn(&Node) -> bool,
+    metrics: &Metrics,
+    events: &EventLog,
+) -> Result<Vec<Node>, ApiError> {
+    let removed = network.store.remove(filter).map_err(|err| {
+        tracing::error!(network = %network.name, error = %err, "failed to persist the removal");
+        ApiError::Internal("failed to persist the removal".to_string())
+    })?;
+    for node in &removed {
+        tracing::info!(network = %network.name, node = %node.addr(), "node removed");
+        metrics.record(Event::Removed);
+        events.publish(&network.name, NodeEventKind::Removed, node, now());
+    }
+    Ok(removed)
+}
+
+#[get("/nodes")]
+async fn list_nodes(
+    _operator: Operator,
+    network: Network,
+    params: web::Query<NodeListParams>,
+) -> Result<HttpResponse, ApiError> {
+    // list every node of the network, a page at a time
+    let range = match params.address.as_deref() {
+        Some(address) => Some(
+            address
+                .parse::<Cidr>()
+                .map_err(|err| ApiError::InvalidQuery(format!("address: {}", err)))?,
+        ),
+        None => None,
+    };
+    let mut nodes = network.store.list();
+    if let Some(range) = range {
+        nodes.retain(|node| node.ip_addresses().into_iter().any(|address| range.contains(address)));
+    }
+    // in a stable order, so that the pages do not overlap
+    nodes.sort_by_key(|node| (node.ipv4_address, node.ipv6_address, node.port));
+    let now = now();
+    let nodes = nodes
+        .into_iter()
+        .map(|node| {
+            let state = node.state(now, &network.config);
+            ListedNode { node, state }
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(Page::of(nodes, params.page, params.per_page)))
+}
+
+/// the node address of the path of a node endpoint
+fn path_addr(path: web::Path<(IpAddr, u16)>) -> NodeAddr {
+    let (address, port) = path.into_inner();
+    match address.to_canonical() {
+        IpAddr::V4(ipv4) => NodeAddr { ipv4_address: Some(ipv4), ipv6_address: None, port },
+        IpAddr::V6(ipv6) => NodeAddr { ipv4_address: None, ipv6_address: Some(ipv6), port },
+    }
+}
+
+#[patch("/nodes/{address}/{port}")]
+async fn edit_node(
+    operator: Operator,
+    network: Network,
+    path: web::Path<(IpAddr, u16)>,
+    info: web::Json<NodeEdit>,
+    admin: web::Data<Admin>,
+    events: web::Data<EventLog>,
+) -> Result<HttpResponse, ApiError> {
+    // edit the metadata and the state of a node
+    let address = path_addr(path);
+    if info.is_empty() {
+        return Err(ApiError::InvalidBody("there is nothing to change".to_string()));
+    }
+    let NodeEdit { protocol_version, services, user_agent, best_height, state } = &*info;
+    let user_agent = user_agent.as_deref().map(validation::check_user_agent).transpose()?;
+    let now = now();
+    let mut previous = None;
+    let edited = network.store.edit(&address, &mut |node| {
+        previous = Some(node.state(now, &network.config));
+        let metadata = &mut node.metadata;
+        metadata.protocol_version = protocol_version.or(metadata.protocol_version);
+        metadata.services = services.unwrap_or(metadata.services);
+        metadata.best_height = best_height.or(metadata.best_height);
+        if let Some(user_agent) = &user_agent {
+            metadata.user_agent = user_agent.clone();
+        }
+        match state {
+            Some(EditedState::Active) => {
+                node.inactive_since = None;
+                node.last_seen = now;
+            }
+            Some(EditedState::Inactive) => {
+                node.inactive_since.get_or_insert(now);
+            }
+            None => {}
+        }
+    });
+    let node = match edited {
+        Ok(Some(node)) => node,
+        Ok(None) => return Err(ApiError::NodeNotFound(address.to_string())),
+        Err(err) => {
+            tracing::error!(network = %network.name, node = %address, error = %err, "failed to persist the edit");
+            return Err(ApiError::Internal("failed to persist the edit".to_string()));
+        }
+    };
+
+    // the subscribers hear about a node entering or leaving the queries
+    let state = node.state(now, &network.config);
+    match (previous, state) {
+        (Some(NodeState::Inactive), NodeState::Active) => {
+            events.publish(&network.name, NodeEventKind::Registered, &node, now)
+        }
+        (Some(NodeState::Active | NodeState::Stale), NodeState::Inactive) => {
+            events.publish(&network.name, NodeEventKind::Deregistered, &node, now)
+        }
+        _ => {}
+    }
+    let detail = serde_json::to_string(&*info).unwrap_or_default();
+    admin.audit.record(
+        &operator,
+        AuditAction::EditNode,
+        address.to_string(),
+        format!("edited in {}: {}", network.name, detail),
+    );
+    Ok(HttpResponse::Ok().json(ListedNode { node, state }))
+}
+
+#[delete("/nodes/{address}/{port}")]
+async fn remove_node(
+    operator: Operator,
+    network: Network,
+    path: web::Path<(IpAddr, u16)>,
+    admin: web::Data<Admin>,
+    metrics: web::Data<Metrics>,
+    events: web::Data<EventLog>,
+) -> Result<HttpResponse, ApiError> {
+    // remove a node right away, without keeping it as inactive
+    let address = path_addr(path);
+    let nodes = remove_nodes(&network, &|node| address.matches(node), &metrics, &events)?;
+    if nodes.is_empty() {
+        return Err(ApiError::NodeNotFound(address.to_string()));
+    }
+    admin.audit.record(
+        &operator,
+        AuditAction::RemoveNode,
+        address.to_string(),
+        format!("removed from {}", network.name),
+    );
+    Ok(HttpResponse::Ok().json(NodesResponse { nodes }))
+}
+
+#[get("/bans")]
+async fn list_bans(_operator: Operator, bans: web::Data<BanList>) -> HttpResponse {
+    HttpResponse::Ok().json(BansResponse { bans: bans.list(now()) })
+}
+
+#[post("/bans")]
+async fn add_ban(
+    operator: Operator,
+    info: web::Json<BanRequest>,
+    admin: web::Data<Admin>,
+    bans: web::Data<BanList>,
+    networks: web::Data<Networks>,
+    metrics: web::Data<Metrics>,
+    events: web::Data<EventLog>,
+) -> Result<HttpResponse, ApiError> {
+    // ban an address range or a public key, and remove the banned nodes of every network
+    let target = info.target()?;
+    let reason = info.reason.trim().to_string();
+    if reason.is_empty() || reason.len() > MAX_REASON_LEN {
+        return Err(ApiError::invalid_field(
+            "reason",
+            format!("the reason must be 1 to {} characters", MAX_REASON_LEN),
+        ));
+    }
+    if info.expires_in == Some(0) {
+        return Err(ApiError::invalid_field("expires_in", "expires_in must be at least 1".to_string()));
+    }
+    let now = now();
+    let expires_at = info.expires_in.map(|expires_in| now.saturating_add(expires_in));
+    let ban = bans.add(target, reason, expires_at, &operator.name, now).map_err(|err| {
+        tracing::error!(error = %err, "failed to persist the ban");
+        ApiError::Internal("failed to persist the ban".to_string())
+    })?;
+
+    let mut removed = 0;
+    for network in networks.iter() {
+        removed += remove_nodes(network, &|node| ban.matches_node(node), &metrics, &events)?.len();
+    }
+    admin.audit.record(
+        &operator,
+        AuditAction::Ban,
+        ban.target.to_string(),
+        format!("ban {}: {} ({} nodes removed)", ban.id, ban.reason, removed),
+    );
+    Ok(HttpResponse::Created().json(BanResponse { ban, removed }))
+}
+
+#[delete("/bans/{id}")]
+async fn remove_ban(
+    operator: Operator,
+    path: web::Path<u64>,
+    admin: web::Data<Admin>,
+    bans: web::Data<BanList>,
+) -> Result<HttpResponse, ApiError> {
+    // lift a ban
+    let id = path.into_inner();
+    let ban = match bans.remove(id, now()) {
+        Ok(Some(ban)) => ban,
+        Ok(None) => return Err(ApiError::BanNotFound(id)),
+        Err(err) => {
+            tracing::error!(error = %err, "failed to persist the lifted ban");
+            return Err(ApiError::Internal("failed to persist the lifted ban".to_string()));
+        }
+    };
+    admin.audit.record(&operator, AuditAction::Unban, ban.target.to_string(), format!("ban {} lifted", ban.id));
+    Ok(HttpResponse::Ok().json(ban))
+}
+
+#[get("/audit")]
+async fn audit_log(
+    _operator: Operator,
+    params: web::Query<AuditParams>,
+    admin: web::Data<Admin>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(Page::of(admin.audit.list(), params.page, params.per_page))
+}
+
+/// the endpoints about the nodes of a network
+fn node_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_nodes).service(edit_node).service(remove_node);
+}
+
+/// the admin API under `/admin`, to register before the legacy routes which would shadow it
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            // a malformed address, port or ban ID matches no node or ban
+            .app_data(web::PathConfig::default().error_handler(|_, _| ApiError::NotFound.into()))
+            .service(list_bans)
+            .service(add_ban)
+            .service(remove_ban)
+            .service(audit_log)
+            .configure(node_routes)
+            .service(web::scope("/{network}").configure(node_routes)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_expire_and_survive_restarts() {
+        let path = std::env::temp_dir().join(format!("rusty_coin_dns_bans_{}.json", std::process::id()));
+        let bans = BanList::open(&path).unwrap();
+        let range = BanTarget::Address("10.1.0.0/16".parse().unwrap());
+        let key = BanTarget::PublicKey("ab".repeat(32));
+        let forever = bans.add(range, "spam".to_string(), None, "alice", 100).unwrap();
+        let expiring = bans.add(key, "sybil".to_string(), Some(200), "bob", 100).unwrap();
+
+        let address = NodeAddr {
+            ipv4_address: Some("10.1.2.3".parse().unwrap()),
+            ipv6_address: None,
+            port: 8333,
+        };
+        assert!(matches!(bans.check(&address, None, 150), Err(ApiError::Banned(reason)) if reason == "spam"));
+        let other = NodeAddr { ipv4_address: Some("10.2.0.1".parse().unwrap()), ..address.clone() };
+        assert!(bans.check(&other, Some(&"AB".repeat(32)), 150).is_err());
+        assert!(bans.check(&other, Some(&"AB".repeat(32)), 200).is_ok());
+
+        // the bans are loaded again, and the IDs are not reused
+        let bans = BanList::open(&path).unwrap();
+        assert_eq!(bans.list(150).len(), 2);
+        assert_eq!(bans.list(200).len(), 1);
+        assert!(bans.remove(expiring.id, 200).unwrap().is_none());
+        assert_eq!(bans.remove(forever.id, 200).unwrap().map(|ban| ban.id), Some(forever.id));
+        let range = BanTarget::Address("10.1.0.0/16".parse().unwrap());
+        let again = bans.add(range, "spam".to_string(), None, "alice", 300).unwrap();
+        assert_eq!(again.id, expiring.id + 1);
+        assert_eq!(BanList::open(&path).unwrap().list(300).len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+}
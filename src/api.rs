@@ -0,0 +1,353 @@
+//! the JSON bodies of the HTTP API
+//! every response of the node endpoints is a JSON object (only `/metrics` is plain text):
+//! - a success is one of the envelopes below, e.g. `{"message": "...", "ttl": 60}`
+//! - an error is `{"error": "<code>", "message": "<reason>"}`, with a `field` naming the
+//!   offending field of an `invalid_field` error, the codes are stable and can be matched on,
+//!   the message is for humans and may change
+//!
+//! | status | code                    | when                                                    |
+//! |--------|-------------------------|---------------------------------------------------------|
+//! | 400    | `invalid_field`         | a field of the body failed validation                   |
+//! | 400    | `invalid_body`          | the body is not the expected JSON                       |
+//! | 400    | `invalid_query`         | the query string cannot be parsed                       |
+//! | 401    | `invalid_signature`     | the signature is missing, invalid or replayed           |
+//! | 401    | `unauthorized`          | the admin token is missing or unknown                   |
+//! | 403    | `key_mismatch`          | the node is bound to another public key                 |
+//! | 403    | `invalid_proof_of_work` | a new node did not solve a challenge                    |
+//! | 403    | `group_full`            | the subnet (or autonomous system) of the node is full   |
+//! | 403    | `banned`                | the address or the public key of the node is banned     |
+//! | 404    | `not_found`             | there is no such endpoint                               |
+//! | 404    | `unknown_network`       | the network is not served                               |
+//! | 404    | `node_not_found`        | the node is not registered (or inactive)                |
+//! | 404    | `no_nodes`              | no node matches the query                               |
+//! | 404    | `pow_disabled`          | proof of work is not enabled                            |
+//! | 404    | `ban_not_found`         | there is no such ban (or it expired)                    |
+//! | 429    | `rate_limited`          | the client is over budget, see `Retry-After`            |
+//! | 500    | `internal_error`        | e.g. the change could not be written to the storage     |
+//! | 503    | `too_many_challenges`   | too many challenges are waiting for a solution          |
+//!
+//! versions:
+//! - the node endpoints are served under `/v1` (e.g. `/v1/query` or `/v1/testnet/query`),
+//!   with the envelopes above
+//! - the routes without a version (e.g. `/query`) are the legacy API, kept for the nodes deployed
+//!   before `/v1`: they are served from the same stores, but keep their former bodies:
+//!   - `/register` returns 200 OK with the plain text
+//!     `register node <IP address>:<port> successfully`, for a new node as well as for a refresh,
+//!     and `/deregister` returns plain text as well
+//!   - `/query` returns a single node object, or the JSON string `"no active nodes in the network"`
+//!     (with 200 OK) if no node matches, a query with `count` returns a bare array instead,
+//!     an empty one if no node matches
+//!   - `/nodes/inactive` returns a bare array
+//!   - the nodes have more fields than they used to (e.g. `health` and the metadata), and the
+//!     `ipv4_address` of a node registered with an IPv6 address only is `null`
+//!   - the errors are the JSON errors above in both versions, where a malformed request used to get
+//!     a plain-text 400 Bad Request
+//! - every response of a legacy route announces that it is deprecated, with a `Deprecation: true`
+//!   header, a `Link` to its successor under `/v1` (`rel="successor-version"`) and, once the date
+//!   is decided, a `Sunset` header with the date the route will be removed
+
+use std::fmt;
+use std::future::{ready, Ready};
+use actix_web::{dev, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready};
+use actix_web::http::header::{HeaderName, HeaderValue, LINK};
+use actix_web::http::StatusCode;
+use futures_util::future::LocalBoxFuture;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// the prefix of the routes of the current version
+pub const V1_PREFIX: &str = "/v1";
+
+/// the body of a legacy `/query` without `count` when no node matches, a JSON string
+pub const LEGACY_NO_NODES: &str = "no active nodes in the network";
+
+/// the version of the API a request was routed to, registered as app data of the scope
+/// of its routes, `V1` if there is none
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    /// the routes without a version
+    Legacy,
+    V1,
+}
+
+impl ApiVersion {
+    /// the version `req` was routed to
+    pub fn of(req: &HttpRequest) -> ApiVersion {
+        req.app_data::<ApiVersion>().copied().unwrap_or(ApiVersion::V1)
+    }
+}
+
+impl FromRequest for ApiVersion {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<ApiVersion, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        ready(Ok(ApiVersion::of(req)))
+    }
+}
+
+/// a success without any data
+#[derive(Debug, Serialize, JsonSchema)]
+#[schemars(rename = "Message", deny_unknown_fields)]
+pub struct MessageResponse {
+    pub message: String,
+}
+
+/// response to a registration or a heartbeat,
+/// `ttl` tells the node how many seconds it has until the next heartbeat is due
+#[derive(Debug, Serialize, JsonSchema)]
+#[schemars(rename = "Lease", deny_unknown_fields)]
+pub struct LeaseResponse {
+    pub message: String,
+    /// the seconds until the next heartbeat is due
+    #[schemars(range(min = 1))]
+    pub ttl: u64,
+}
+
+/// a list of nodes
+#[derive(Debug, Serialize, JsonSchema)]
+#[schemars(rename = "{T}s", deny_unknown_fields)]
+pub struct NodesResponse<T> {
+    pub nodes: Vec<T>,
+}
+
+/// every error of the HTTP API, see the module documentation for the codes
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidField { field: &'static str, message: String },
+    InvalidBody(String),
+    InvalidQuery(String),
+    InvalidSignature(String),
+    KeyMismatch(String),
+    InvalidProofOfWork(String),
+    GroupFull(String),
+    Unauthorized,
+    /// with the reason of the ban
+    Banned(String),
+    NotFound,
+    UnknownNetwork(String),
+    NodeNotFound(String),
+    NoNodes,
+    PowDisabled,
+    BanNotFound(u64),
+    /// with the number of seconds to wait
+    RateLimited(u64),
+    Internal(String),
+    TooManyChallenges,
+}
+
+/// the codes of `ApiError::code`
+pub const ERROR_CODES: &[&str] = &[
+    "invalid_field",
+    "invalid_body",
+    "invalid_query",
+    "invalid_signature",
+    "unauthorized",
+    "key_mismatch",
+    "invalid_proof_of_work",
+    "group_full",
+    "banned",
+    "not_found",
+    "unknown_network",
+    "node_not_found",
+    "no_nodes",
+    "pow_disabled",
+    "ban_not_found",
+    "rate_limited",
+    "internal_error",
+    "too_many_challenges",
+];
+
+/// the body of an error response
+#[derive(Debug, Serialize, JsonSchema)]
+#[schemars(rename = "Error", deny_unknown_fields)]
+pub struct ErrorBody<'a> {
+    #[schemars(extend("enum" = ERROR_CODES))]
+    error: &'static str,
+    /// the offending field of an `invalid_field` error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'static str>,
+    message: &'a str,
+}
+
+impl ApiError {
+    pub fn invalid_field(field: &'static str, message: String) -> ApiError {
+        ApiError::InvalidField { field, message }
+    }
+
+    /// the stable code of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidField { .. } => "invalid_field",
+            ApiError::InvalidBody(_) => "invalid_body",
+            ApiError::InvalidQuery(_) => "invalid_query",
+            ApiError::InvalidSignature(_) => "invalid_signature",
+            ApiError::KeyMismatch(_) => "key_mismatch",
+            ApiError::InvalidProofOfWork(_) => "invalid_proof_of_work",
+            ApiError::GroupFull(_) => "group_full",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Banned(_) => "banned",
+            ApiError::NotFound => "not_found",
+            ApiError::UnknownNetwork(_) => "unknown_network",
+            ApiError::NodeNotFound(_) => "node_not_found",
+            ApiError::NoNodes => "no_nodes",
+            ApiError::PowDisabled => "pow_disabled",
+            ApiError::BanNotFound(_) => "ban_not_found",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::Internal(_) => "internal_error",
+            ApiError::TooManyChallenges => "too_many_challenges",
+        }
+    }
+
+    /// the human-readable reason of the error
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::InvalidField { message, .. }
+            | ApiError::InvalidBody(message)
+            | ApiError::InvalidQuery(message)
+            | ApiError::InvalidSignature(message)
+            | ApiError::KeyMismatch(message)
+            | ApiError::InvalidProofOfWork(message)
+            | ApiError::GroupFull(message)
+            | ApiError::Internal(message) => message.clone(),
+            ApiError::Unauthorized => "a valid admin token is required".to_string(),
+            ApiError::Banned(reason) => format!("the node is banned: {}", reason),
+            ApiError::NotFound => "there is no such endpoint".to_string(),
+            ApiError::UnknownNetwork(network) => format!("network {:?} is not served here", network),
+            ApiError::NodeNotFound(node) => format!("node {} is not registered", node),
+            ApiError::NoNodes => "no node matches the query".to_string(),
+            ApiError::PowDisabled => "proof of work is not enabled".to_string(),
+            ApiError::BanNotFound(id) => format!("there is no ban {}", id),
+            ApiError::RateLimited(retry_after) => {
+                format!("too many requests, retry in {} seconds", retry_after)
+            }
+            ApiError::TooManyChallenges => "too many pending challenges, try again later".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidField { .. } | ApiError::InvalidBody(_) | ApiError::InvalidQuery(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ApiError::InvalidSignature(_) | ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::KeyMismatch(_)
+            | ApiError::InvalidProofOfWork(_)
+            | ApiError::GroupFull(_)
+            | ApiError::Banned(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound
+            | ApiError::UnknownNetwork(_)
+            | ApiError::NodeNotFound(_)
+            | ApiError::NoNodes
+            | ApiError::PowDisabled
+            | ApiError::BanNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::TooManyChallenges => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            ApiError::RateLimited(retry_after) => {
+                response.insert_header(("Retry-After", retry_after.to_string()));
+            }
+            ApiError::Unauthorized => {
+                response.insert_header(("WWW-Authenticate", "Bearer"));
+            }
+            _ => {}
+        }
+        let field = match self {
+            ApiError::InvalidField { field, .. } => Some(*field),
+            _ => None,
+        };
+        response.json(ErrorBody {
+            error: self.code(),
+            field,
+            message: &self.message(),
+        })
+    }
+}
+
+/// the middleware announcing that the routes it wraps are deprecated in favour of `/v1`
+pub struct Deprecated {
+    /// the `Sunset` header, if the date is decided
+    sunset: Option<HeaderValue>,
+}
+
+impl Deprecated {
+    /// `sunset` is an HTTP date, or empty if the date is not decided yet
+    pub fn new(sunset: &str) -> Deprecated {
+        Deprecated {
+            sunset: HeaderValue::from_str(sunset).ok().filter(|_| !sunset.is_empty()),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Deprecated
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = DeprecatedMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(DeprecatedMiddleware {
+            service,
+            sunset: self.sunset.clone(),
+        }))
+    }
+}
+
+pub struct DeprecatedMiddleware<S> {
+    service: S,
+    sunset: Option<HeaderValue>,
+}
+
+impl<S, B> Service<ServiceRequest> for DeprecatedMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let sunset = self.sunset.clone();
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let mut response = response.await?;
+            // only a route has a successor, not a path that matched nothing
+            let successor = response
+                .request()
+                .match_pattern()
+                .map(|_| format!("<{}{}>; rel=\"successor-version\"", V1_PREFIX, response.request().path()));
+            let headers = response.headers_mut();
+            headers.insert(HeaderName::from_static("deprecation"), HeaderValue::from_static("true"));
+            if let Some(link) = successor.and_then(|link| HeaderValue::from_str(&link).ok()) {
+                headers.insert(LINK, link);
+            }
+            if let Some(sunset) = sunset {
+                headers.insert(HeaderName::from_static("sunset"), sunset);
+            }
+            Ok(response)
+        })
+    }
+}
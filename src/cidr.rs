@@ -0,0 +1,99 @@
+//! IP address ranges in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    /// the first address of the range, with the host bits cleared
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// the range of the addresses sharing the first `prefix_len` bits with `ip`
+    pub fn new(ip: IpAddr, prefix_len: u8) -> Cidr {
+        let ip = ip.to_canonical();
+        let prefix_len = prefix_len.min(max_prefix_len(ip));
+        Cidr {
+            network: mask(ip, prefix_len),
+            prefix_len,
+        }
+    }
+
+    /// whether `ip` is in the range
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_ipv4() == self.network.is_ipv4() && mask(ip, self.prefix_len) == self.network
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+}
+
+/// the number of bits of an address of the family of `ip`
+fn max_prefix_len(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// clear all but the first `prefix_len` bits of `ip`
+fn mask(ip: IpAddr, prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let bits = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V4((u32::from(ip) & bits).into())
+        }
+        IpAddr::V6(ip) => {
+            let bits = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V6((u128::from(ip) & bits).into())
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// parse `<address>/<prefix length>`, or a single address
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (ip, prefix_len) = match s.split_once('/') {
+            Some((ip, prefix_len)) => (ip, Some(prefix_len)),
+            None => (s, None),
+        };
+        let ip: IpAddr = ip.parse().map_err(|_| format!("{:?} is not an IP address", ip))?;
+        let max = max_prefix_len(ip.to_canonical());
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => match prefix_len.parse::<u8>() {
+                Ok(prefix_len) if prefix_len <= max => prefix_len,
+                _ => return Err(format!("{:?} is not a prefix length between 0 and {}", prefix_len, max)),
+            },
+            None => max,
+        };
+        Ok(Cidr::new(ip, prefix_len))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// a range is (de)serialized in CIDR notation
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,41 @@
+//! the address a request came from
+//! it is the address of the TCP peer, unless the peer is a trusted reverse proxy,
+//! in which case it is the right-most address in `X-Forwarded-For` that is not a trusted proxy
+
+use std::net::IpAddr;
+use actix_web::HttpRequest;
+use crate::cidr::Cidr;
+
+/// the address `req` came from, `None` if the connection has no peer address (e.g. in tests)
+pub fn client_ip(req: &HttpRequest, trusted_proxies: &[Cidr]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip().to_canonical();
+    if !is_trusted(peer, trusted_proxies) {
+        return Some(peer);
+    }
+
+    // every proxy appends the address it received the request from,
+    // so only the right-most entries (added by trusted proxies) can be relied on
+    let mut client = peer;
+    let forwarded = req.headers().get_all("x-forwarded-for");
+    let hops: Vec<&str> = forwarded
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    for hop in hops.into_iter().rev() {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                client = ip.to_canonical();
+                if !is_trusted(client, trusted_proxies) {
+                    break;
+                }
+            }
+            // a garbled entry cannot be trusted, neither can anything left of it
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+fn is_trusted(ip: IpAddr, trusted_proxies: &[Cidr]) -> bool {
+    trusted_proxies.iter().any(|proxy| proxy.contains(ip))
+}
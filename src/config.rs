@@ -0,0 +1,852 @@
+//! runtime configuration of the DNS server
+//! by default the nodes are only kept in memory and served over HTTP on `127.0.0.1:8080`,
+//! the DNS listener, the disk storage, the probing of the nodes, the rate limits
+//! and the proof of work are off until enabled, e.g. with `dns_enabled = true`
+//!
+//! every setting has a default value, which can be overridden, from lowest to highest precedence:
+//! 1. by a TOML file given with `--config <path>` (or `RUSTY_COIN_DNS_CONFIG`),
+//!    with one top-level key per setting, e.g. `node_ttl_secs = 120`
+//! 2. by an environment variable named `RUSTY_COIN_DNS_<SETTING>`,
+//!    e.g. `RUSTY_COIN_DNS_NODE_TTL_SECS=120`
+//! 3. by a command line flag, e.g. `--node-ttl-secs 120` or `--node-ttl-secs=120`
+//!
+//! lists are TOML arrays in the file, and comma separated in the environment and on the command
+//! line, `--check-config` validates the configuration and prints the effective settings
+//! in the format of the file
+//!
+//! the settings of a network (see the `network` module) can be overridden in a `[network.<name>]`
+//! table of the file, or by a flag like `--network.testnet.node-ttl-secs 30`, e.g.
+//! ```toml
+//! networks = ["mainnet", "testnet", "regtest"]
+//!
+//! [network.regtest]
+//! allow_private_addresses = true
+//! ```
+//! the settings of the process (the listeners, the logs, the proxies and the rate limits)
+//! cannot be overridden per network
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use actix_web::http::header::HttpDate;
+use crate::admin::AdminToken;
+use crate::cidr::Cidr;
+use crate::diversity::SamplingMode;
+use crate::logging::{self, LogFormat};
+use crate::validation::MismatchPolicy;
+
+/// prefix of every environment variable read by the DNS server
+const ENV_PREFIX: &str = "RUSTY_COIN_DNS_";
+
+/// the settings shared by all the networks, which cannot be overridden per network
+const PROCESS_SETTINGS: &[&str] = &[
+    "http_bind_address",
+    "http_workers",
+    "log_level",
+    "log_format",
+    "dns_enabled",
+    "dns_bind_address",
+    "seed_domain",
+    "dns_record_ttl_secs",
+    "dns_max_answers",
+    "dns_node_port",
+    "trusted_proxies",
+    "signature_max_skew_secs",
+    "rate_limit_enabled",
+    "rate_limit_register_per_min",
+    "rate_limit_heartbeat_per_min",
+    "rate_limit_deregister_per_min",
+    "rate_limit_query_per_min",
+    "rate_limit_ipv4_prefix_len",
+    "rate_limit_ipv6_prefix_len",
+    "rate_limit_allowlist",
+    "legacy_api_sunset",
+    "event_log_capacity",
+    "event_keepalive_secs",
+    "admin_tokens",
+    "admin_audit_capacity",
+    "networks",
+];
+
+/// the first segments of the routes without a network prefix (under `/admin` as well),
+/// which cannot name a network
+const RESERVED_NETWORK_NAMES: &[&str] = &[
+    "register", "heartbeat", "deregister", "query", "nodes", "metrics", "events", "v1",
+    "admin", "bans", "audit",
+];
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// the address of the HTTP API, e.g. `0.0.0.0:8080`
+    pub http_bind_address: String,
+    /// the number of HTTP worker threads, 0 for one per CPU core
+    pub http_workers: usize,
+    /// the minimum level of the logged events (`error`, `warn`, `info`, `debug` or `trace`),
+    /// optionally per module, e.g. `info,rusty_coin_dns::dns=debug`
+    pub log_level: String,
+    /// the format of the logged events: `json` or `text`
+    pub log_format: LogFormat,
+    /// how long (in seconds) a node stays registered without sending a heartbeat
+    pub node_ttl_secs: u64,
+    /// how often (in seconds) the reaper looks for nodes whose TTL has lapsed
+    pub reap_interval_secs: u64,
+    /// how long (in seconds) a node stays stale after its TTL lapsed,
+    /// a heartbeat within this period makes it active again, after it the node is inactive
+    pub stale_grace_secs: u64,
+    /// how long (in seconds) an inactive node is kept before it is purged
+    pub inactive_retention_secs: u64,
+    /// whether `/query` hands out recently inactive nodes when no node is active
+    pub query_fallback_enabled: bool,
+    /// how recently (in seconds) a stale or inactive node must have been seen
+    /// to be handed out by the `/query` fallback
+    pub query_fallback_window_secs: u64,
+    /// whether to serve the active nodes over the DNS wire protocol
+    pub dns_enabled: bool,
+    /// the UDP and TCP address of the DNS listener, e.g. `0.0.0.0:53`
+    pub dns_bind_address: String,
+    /// the domain whose A and AAAA records are the active nodes
+    pub seed_domain: String,
+    /// TTL (in seconds) of the A and AAAA records
+    pub dns_record_ttl_secs: u32,
+    /// maximum number of records in a DNS answer
+    pub dns_max_answers: usize,
+    /// the default P2P port of the network: an A or AAAA record carries no port, so the resolvers
+    /// connect to this one, the nodes registered on any other port are only handed out over HTTP
+    pub dns_node_port: u16,
+    /// whether to probe the nodes and only hand out the nodes that passed their recent probes
+    pub probe_enabled: bool,
+    /// how often (in seconds) every node is probed
+    pub probe_interval_secs: u64,
+    /// how long (in milliseconds) a probe waits for the TCP connection to be established
+    pub probe_timeout_ms: u64,
+    /// maximum number of probes in flight at the same time
+    pub probe_concurrency: usize,
+    /// number of probes a node may fail in a row before it is no longer handed out
+    pub probe_failure_threshold: u32,
+    /// whether to store the nodes on disk, so that they survive restarts
+    pub storage_enabled: bool,
+    /// the directory holding the snapshot and the log of the nodes
+    pub storage_dir: String,
+    /// how often (in seconds) the log is folded into a new snapshot
+    pub compaction_interval_secs: u64,
+    /// private network mode: accept loopback, private, shared, link-local, multicast,
+    /// documentation, benchmarking and reserved addresses, e.g. for a local test network
+    pub allow_private_addresses: bool,
+    /// maximum number of nodes returned by a single `GET /query`
+    pub max_query_count: usize,
+    /// what to do when the address in the body of a request differs from the address
+    /// the request came from: `allow` it, `override` it or `reject` the request
+    pub address_mismatch_policy: MismatchPolicy,
+    /// the reverse proxies (addresses or CIDR ranges, comma separated in the environment)
+    /// whose `X-Forwarded-For` header is trusted to tell the address a request came from
+    pub trusted_proxies: Vec<Cidr>,
+    /// whether every registration must be signed with the key of the node
+    pub require_signatures: bool,
+    /// how far (in seconds) the timestamp of a signed request may be from the server time
+    pub signature_max_skew_secs: u64,
+    /// whether a new node must solve a proof-of-work challenge before it is registered
+    pub pow_enabled: bool,
+    /// the number of leading zero bits of a solution while the registration rate is normal
+    pub pow_difficulty: u32,
+    /// the difficulty never grows past this number of bits
+    pub pow_max_difficulty: u32,
+    /// the number of new nodes per minute above which the difficulty grows
+    pub pow_target_rate: usize,
+    /// how long (in seconds) a challenge can be solved in
+    pub pow_challenge_ttl_secs: u64,
+    /// the prefix length of the IPv4 subnets the diversity caps apply to
+    pub ipv4_subnet_prefix_len: u8,
+    /// the prefix length of the IPv6 subnets the diversity caps apply to
+    pub ipv6_subnet_prefix_len: u8,
+    /// maximum number of nodes registered per IPv4 subnet (or autonomous system), 0 for no limit
+    pub max_nodes_per_ipv4_subnet: usize,
+    /// maximum number of nodes registered per IPv6 subnet (or autonomous system), 0 for no limit
+    pub max_nodes_per_ipv6_subnet: usize,
+    /// a file mapping address ranges to autonomous systems, empty for none,
+    /// see the `diversity` module
+    pub asn_map_file: String,
+    /// how a multi-node query picks its nodes: `uniform` or `diverse` (across subnets)
+    pub query_sampling: SamplingMode,
+    /// whether to rate-limit the node endpoints per client, see the `ratelimit` module
+    pub rate_limit_enabled: bool,
+    /// the budgets of the node endpoints, in requests per minute per client, 0 for no limit
+    pub rate_limit_register_per_min: u32,
+    pub rate_limit_heartbeat_per_min: u32,
+    pub rate_limit_deregister_per_min: u32,
+    pub rate_limit_query_per_min: u32,
+    /// the prefix length of the IPv4 subnets sharing a budget
+    pub rate_limit_ipv4_prefix_len: u8,
+    /// the prefix length of the IPv6 subnets sharing a budget
+    pub rate_limit_ipv6_prefix_len: u8,
+    /// the clients (addresses or CIDR ranges, comma separated in the environment)
+    /// that are never rate-limited
+    pub rate_limit_allowlist: Vec<Cidr>,
+    /// the date the routes without a version (e.g. `/query` rather than `/v1/query`) will be
+    /// removed, announced in their `Sunset` header, as an HTTP date
+    /// (e.g. `Sun, 01 Mar 2026 00:00:00 GMT`), empty if not decided yet
+    pub legacy_api_sunset: String,
+    /// the number of node events kept for the subscribers of `/v1/events` that resume their stream,
+    /// which is also how far behind a subscriber can fall, see the `events` module
+    pub event_log_capacity: usize,
+    /// after how many seconds without an event a comment is sent on the event streams
+    pub event_keepalive_secs: u64,
+    /// the tokens of the operators allowed to use the admin API, as `<operator>:<token>`
+    /// (comma separated in the environment), the admin API is not served if there is none,
+    /// the tokens are redacted by `--check-config`, see the `admin` module
+    pub admin_tokens: Vec<AdminToken>,
+    /// the number of admin actions kept in the audit log
+    pub admin_audit_capacity: usize,
+    /// the networks served, any other network is rejected, the first one is the default network
+    /// (served without a prefix and over DNS), see the `network` module
+    pub networks: Vec<String>,
+    /// the settings overridden per network, as `(setting, value)` pairs
+    pub network_overrides: BTreeMap<String, Vec<(String, String)>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            http_bind_address: "127.0.0.1:8080".to_string(),
+            http_workers: 0,
+            log_level: "info".to_string(),
+            log_format: LogFormat::Text,
+            node_ttl_secs: 60,
+            reap_interval_secs: 10,
+            stale_grace_secs: 60,
+            inactive_retention_secs: 24 * 60 * 60,
+            query_fallback_enabled: true,
+            query_fallback_window_secs: 60 * 60,
+            dns_enabled: false,
+            dns_bind_address: "127.0.0.1:5353".to_string(),
+            seed_domain: "seed.rustycoin.example".to_string(),
+            dns_record_ttl_secs: 60,
+            dns_max_answers: 16,
+            dns_node_port: 8333,
+            probe_enabled: false,
+            probe_interval_secs: 30,
+            probe_timeout_ms: 3000,
+            probe_concurrency: 32,
+            probe_failure_threshold: 3,
+            storage_enabled: false,
+            storage_dir: "data".to_string(),
+            compaction_interval_secs: 300,
+            allow_private_addresses: false,
+            max_query_count: 32,
+            address_mismatch_policy: MismatchPolicy::Allow,
+            trusted_proxies: Vec::new(),
+            require_signatures: false,
+            signature_max_skew_secs: 300,
+            pow_enabled: false,
+            pow_difficulty: 20,
+            pow_max_difficulty: 28,
+            pow_target_rate: 60,
+            pow_challenge_ttl_secs: 120,
+            ipv4_subnet_prefix_len: 16,
+            ipv6_subnet_prefix_len: 32,
+            max_nodes_per_ipv4_subnet: 32,
+            max_nodes_per_ipv6_subnet: 32,
+            asn_map_file: String::new(),
+            query_sampling: SamplingMode::Diverse,
+            rate_limit_enabled: false,
+            rate_limit_register_per_min: 10,
+            rate_limit_heartbeat_per_min: 240,
+            rate_limit_deregister_per_min: 10,
+            rate_limit_query_per_min: 60,
+            rate_limit_ipv4_prefix_len: 32,
+            rate_limit_ipv6_prefix_len: 64,
+            rate_limit_allowlist: Vec::new(),
+            legacy_api_sunset: String::new(),
+            event_log_capacity: 1024,
+            event_keepalive_secs: 15,
+            admin_tokens: Vec::new(),
+            admin_audit_capacity: 1000,
+            networks: vec!["mainnet".to_string()],
+            network_overrides: BTreeMap::new(),
+        }
+    }
+}
+
+/// the settings, by name
+macro_rules! settings {
+    ($($name:ident),* $(,)?) => {
+        impl Config {
+            /// the names of all the settings, in the order they are printed
+            const NAMES: &'static [&'static str] = &[$(stringify!($name)),*];
+
+            /// set the setting `name` from its textual value
+            fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+                match name {
+                    $(stringify!($name) => self.$name = Setting::parse(value)?,)*
+                    _ => return Err(format!("unknown setting {:?}", name)),
+                }
+                Ok(())
+            }
+
+            /// the effective settings, in the format of the configuration file,
+            /// fails if a setting cannot be written in it
+            pub fn to_toml(&self) -> Result<toml::Table, String> {
+                let mut table = toml::Table::new();
+                $(
+                    let value = self.$name.to_toml().map_err(|err| format!("{}: {}", stringify!($name), err))?;
+                    table.insert(stringify!($name).to_string(), value);
+                )*
+                let mut networks = toml::Table::new();
+                for (network, overrides) in &self.network_overrides {
+                    let settings = match self.network(network) {
+                        Ok(config) => config.to_toml().map_err(|err| format!("network.{}.{}", network, err))?,
+                        Err(_) => continue,
+                    };
+                    let overridden = overrides
+                        .iter()
+                        .filter_map(|(name, _)| Some((name.clone(), settings.get(name)?.clone())))
+                        .collect();
+                    networks.insert(network.clone(), toml::Value::Table(overridden));
+                }
+                if !networks.is_empty() {
+                    table.insert("network".to_string(), toml::Value::Table(networks));
+                }
+                Ok(table)
+            }
+        }
+    };
+}
+
+settings!(
+    http_bind_address,
+    http_workers,
+    log_level,
+    log_format,
+    node_ttl_secs,
+    reap_interval_secs,
+    stale_grace_secs,
+    inactive_retention_secs,
+    query_fallback_enabled,
+    query_fallback_window_secs,
+    dns_enabled,
+    dns_bind_address,
+    seed_domain,
+    dns_record_ttl_secs,
+    dns_max_answers,
+    dns_node_port,
+    probe_enabled,
+    probe_interval_secs,
+    probe_timeout_ms,
+    probe_concurrency,
+    probe_failure_threshold,
+    storage_enabled,
+    storage_dir,
+    compaction_interval_secs,
+    allow_private_addresses,
+    max_query_count,
+    address_mismatch_policy,
+    trusted_proxies,
+    require_signatures,
+    signature_max_skew_secs,
+    pow_enabled,
+    pow_difficulty,
+    pow_max_difficulty,
+    pow_target_rate,
+    pow_challenge_ttl_secs,
+    ipv4_subnet_prefix_len,
+    ipv6_subnet_prefix_len,
+    max_nodes_per_ipv4_subnet,
+    max_nodes_per_ipv6_subnet,
+    asn_map_file,
+    query_sampling,
+    rate_limit_enabled,
+    rate_limit_register_per_min,
+    rate_limit_heartbeat_per_min,
+    rate_limit_deregister_per_min,
+    rate_limit_query_per_min,
+    rate_limit_ipv4_prefix_len,
+    rate_limit_ipv6_prefix_len,
+    rate_limit_allowlist,
+    legacy_api_sunset,
+    event_log_capacity,
+    event_keepalive_secs,
+    admin_tokens,
+    admin_audit_capacity,
+    networks,
+);
+
+/// what the command line asks for
+#[derive(Debug)]
+pub enum Command {
+    /// run the server
+    Run(Config),
+    /// print the effective configuration and exit
+    CheckConfig(Config),
+    /// print the usage and exit
+    Help,
+}
+
+/// the usage printed by `--help`
+pub const USAGE: &str = "\
+usage: rusty_coin_dns [--config <path>] [--check-config] [--<setting> <value>]...
+
+  --config <path>       read the settings from a TOML file
+  --check-config        validate the configuration, print the effective settings and exit
+  --<setting> <value>   override a setting, e.g. --node-ttl-secs 120
+  --network.<network>.<setting> <value>
+                        override a setting of a network, e.g. --network.testnet.node-ttl-secs 30
+  --help                print this message
+
+every setting can also be set by the environment variable RUSTY_COIN_DNS_<SETTING>,
+run with --check-config to list the settings";
+
+impl Config {
+    /// build the configuration from the command line arguments (without the program name),
+    /// the configuration file and the environment variables `vars` (e.g. those of the process)
+    pub fn load(
+        args: impl IntoIterator<Item = String>,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Command, String> {
+        let vars: BTreeMap<String, String> = vars.into_iter().filter(|(key, _)| key.starts_with(ENV_PREFIX)).collect();
+        let mut file = vars.get(&format!("{}CONFIG", ENV_PREFIX)).cloned();
+        let mut check = false;
+        let mut flags = Vec::new();
+        let mut network_flags = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let flag = match arg.strip_prefix("--") {
+                Some(flag) => flag,
+                None => return Err(format!("unexpected argument {:?}", arg)),
+            };
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name.replace('-', "_"), Some(value.to_string())),
+                None => (flag.replace('-', "_"), None),
+            };
+            match name.as_str() {
+                "help" => return Ok(Command::Help),
+                "check_config" => check = true,
+                _ => {
+                    let value = match value.or_else(|| args.next()) {
+                        Some(value) => value,
+                        None => return Err(format!("--{} requires a value", flag)),
+                    };
+                    if name == "config" {
+                        file = Some(value);
+                    } else if let Some((network, name)) =
+                        name.strip_prefix("network.").and_then(|name| name.split_once('.'))
+                    {
+                        network_flags.push((network.to_string(), name.to_string(), value));
+                    } else {
+                        flags.push((name, value));
+                    }
+                }
+            }
+        }
+
+        let mut config = Config::default();
+        if let Some(file) = file {
+            config.apply_file(&file)?;
+        }
+        config.apply_env(&vars)?;
+        for (name, value) in flags {
+            config
+                .set(&name, &value)
+                .map_err(|err| format!("--{}: {}", name.replace('_', "-"), err))?;
+        }
+        for (network, name, value) in network_flags {
+            config.override_setting(&network, &name, &value);
+        }
+        config.validate()?;
+
+        Ok(if check { Command::CheckConfig(config) } else { Command::Run(config) })
+    }
+
+    /// apply the settings of the TOML file at `path`
+    fn apply_file(&mut self, path: &str) -> Result<(), String> {
+        let contents = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        let table: toml::Table = contents.parse().map_err(|err| format!("{}: {}", path, err))?;
+        for (name, value) in table {
+            if let ("network", toml::Value::Table(networks)) = (name.as_str(), &value) {
+                for (network, settings) in networks {
+                    let settings = match settings {
+                        toml::Value::Table(settings) => settings,
+                        _ => return Err(format!("{}: network.{}: expected a table", path, network)),
+                    };
+                    for (name, value) in settings {
+                        let value = toml_to_string(value)
+                            .map_err(|err| format!("{}: network.{}.{}: {}", path, network, name, err))?;
+                        self.override_setting(network, name, &value);
+                    }
+                }
+                continue;
+            }
+            let value = toml_to_string(&value);
+            value
+                .and_then(|value| self.set(&name, &value))
+                .map_err(|err| format!("{}: {}: {}", path, name, err))?;
+        }
+        Ok(())
+    }
+
+    /// apply the settings of the `RUSTY_COIN_DNS_<SETTING>` environment variables among `vars`
+    fn apply_env(&mut self, vars: &BTreeMap<String, String>) -> Result<(), String> {
+        for name in Config::NAMES {
+            let key = format!("{}{}", ENV_PREFIX, name.to_ascii_uppercase());
+            if let Some(value) = vars.get(&key) {
+                self.set(name, value).map_err(|err| format!("{}: {}", key, err))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// override the setting `name` of `network`, a later override of the same setting wins
+    fn override_setting(&mut self, network: &str, name: &str, value: &str) {
+        let overrides = self.network_overrides.entry(network.to_string()).or_default();
+        overrides.retain(|(overridden, _)| overridden != name);
+        overrides.push((name.to_string(), value.to_string()));
+    }
+
+    /// the settings of `network`: these settings with the overrides of the network applied,
+    /// a network other than the default one stores its nodes in `<storage_dir>/<network>`
+    /// unless it overrides `storage_dir`
+    pub fn network(&self, network: &str) -> Result<Config, String> {
+        let mut config = Config {
+            network_overrides: BTreeMap::new(),
+            ..self.clone()
+        };
+        if self.networks.first().is_some_and(|default| default != network) {
+            config.storage_dir = Path::new(&self.storage_dir).join(network).display().to_string();
+        }
+        for (name, value) in self.network_overrides.get(network).into_iter().flatten() {
+            if PROCESS_SETTINGS.contains(&name.as_str()) {
+                return Err(format!("network.{}.{}: cannot be set per network", network, name));
+            }
+            config
+                .set(name, value)
+                .map_err(|err| format!("network.{}.{}: {}", network, name, err))?;
+        }
+        Ok(config)
+    }
+
+    /// check the settings, and the settings of every network
+    fn validate(&self) -> Result<(), String> {
+        self.validate_settings()?;
+
+        let mut errors = Vec::new();
+        if self.networks.is_empty() {
+            errors.push("networks: at least one network is required".to_string());
+        }
+        let mut storage_dirs = Vec::new();
+        for (index, network) in self.networks.iter().enumerate() {
+            let valid = !network.is_empty()
+                && network.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'_');
+            if !valid {
+                errors.push(format!("networks: {:?} is not made of a-z, 0-9 and _", network));
+            } else if RESERVED_NETWORK_NAMES.contains(&network.as_str()) {
+                errors.push(format!("networks: {:?} is the name of an endpoint", network));
+            } else if self.networks[..index].contains(network) {
+                errors.push(format!("networks: {:?} is listed twice", network));
+            }
+            match self.network(network) {
+                Ok(config) => {
+                    if let Err(err) = config.validate_settings() {
+                        errors.extend(err.lines().map(|err| format!("network.{}.{}", network, err)));
+                    }
+                    if config.storage_enabled {
+                        if storage_dirs.contains(&config.storage_dir) {
+                            errors.push(format!("network.{}.storage_dir: is shared with another network", network));
+                        }
+                        storage_dirs.push(config.storage_dir);
+                    }
+                }
+                Err(err) => errors.push(err),
+            }
+        }
+        for network in self.network_overrides.keys() {
+            if !self.networks.contains(network) {
+                errors.push(format!("network.{}: is not one of the networks", network));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /// check the settings that are valid on their own but not together or not in this range
+    fn validate_settings(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for (name, address) in [
+            ("http_bind_address", &self.http_bind_address),
+            ("dns_bind_address", &self.dns_bind_address),
+        ] {
+            if address.parse::<SocketAddr>().is_err() {
+                errors.push(format!("{}: {:?} is not an <IP address>:<port>", name, address));
+            }
+        }
+        for (name, prefix_len, max) in [
+            ("ipv4_subnet_prefix_len", self.ipv4_subnet_prefix_len, 32),
+            ("ipv6_subnet_prefix_len", self.ipv6_subnet_prefix_len, 128),
+            ("rate_limit_ipv4_prefix_len", self.rate_limit_ipv4_prefix_len, 32),
+            ("rate_limit_ipv6_prefix_len", self.rate_limit_ipv6_prefix_len, 128),
+        ] {
+            if prefix_len > max {
+                errors.push(format!("{}: {} is longer than {} bits", name, prefix_len, max));
+            }
+        }
+        if let Err(err) = logging::check_filter(&self.log_level) {
+            errors.push(format!("log_level: {}", err));
+        }
+        if self.node_ttl_secs == 0 {
+            errors.push("node_ttl_secs: must be at least 1".to_string());
+        }
+        if self.event_log_capacity == 0 {
+            errors.push("event_log_capacity: must be at least 1".to_string());
+        }
+        if self.event_keepalive_secs == 0 {
+            errors.push("event_keepalive_secs: must be at least 1".to_string());
+        }
+        for (index, token) in self.admin_tokens.iter().enumerate() {
+            if self.admin_tokens[..index].iter().any(|other| other.operator == token.operator) {
+                errors.push(format!("admin_tokens: operator {:?} is listed twice", token.operator));
+            }
+        }
+        if self.admin_audit_capacity == 0 {
+            errors.push("admin_audit_capacity: must be at least 1".to_string());
+        }
+        if self.max_query_count == 0 {
+            errors.push("max_query_count: must be at least 1".to_string());
+        }
+        if self.dns_node_port == 0 {
+            errors.push("dns_node_port: must be at least 1".to_string());
+        }
+        if self.dns_enabled && self.seed_domain.trim().is_empty() {
+            errors.push("seed_domain: must not be empty while dns_enabled is set".to_string());
+        }
+        if self.storage_enabled && self.storage_dir.trim().is_empty() {
+            errors.push("storage_dir: must not be empty while storage_enabled is set".to_string());
+        }
+        if self.pow_difficulty > 256 || self.pow_max_difficulty > 256 {
+            errors.push("pow_difficulty: a SHA-256 hash has only 256 bits".to_string());
+        }
+        if self.pow_max_difficulty < self.pow_difficulty {
+            errors.push("pow_max_difficulty: must not be lower than pow_difficulty".to_string());
+        }
+        if !self.legacy_api_sunset.is_empty() && self.legacy_api_sunset.parse::<HttpDate>().is_err() {
+            errors.push(format!(
+                "legacy_api_sunset: {:?} is not an HTTP date, e.g. \"Sun, 01 Mar 2026 00:00:00 GMT\"",
+                self.legacy_api_sunset
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+}
+
+/// a value of a setting, as written in the environment or on the command line
+trait Setting: Sized {
+    fn parse(value: &str) -> Result<Self, String>;
+
+    fn to_toml(&self) -> Result<toml::Value, String>;
+}
+
+macro_rules! integer_setting {
+    ($($ty:ty),*) => {
+        $(impl Setting for $ty {
+            fn parse(value: &str) -> Result<Self, String> {
+                value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("{:?} is not an integer between {} and {}", value, <$ty>::MIN, <$ty>::MAX))
+            }
+
+            /// a TOML integer is signed, a larger value is reported rather than wrapped
+            fn to_toml(&self) -> Result<toml::Value, String> {
+                i64::try_from(*self)
+                    .map(toml::Value::Integer)
+                    .map_err(|_| format!("{} is larger than a TOML integer ({})", self, i64::MAX))
+            }
+        })*
+    };
+}
+
+integer_setting!(u8, u16, u32, u64, usize);
+
+impl Setting for bool {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" => Ok(true),
+            "false" | "0" | "no" | "off" => Ok(false),
+            _ => Err(format!("{:?} is not true or false", value)),
+        }
+    }
+
+    fn to_toml(&self) -> Result<toml::Value, String> {
+        Ok(toml::Value::Boolean(*self))
+    }
+}
+
+macro_rules! text_setting {
+    ($($ty:ty),*) => {
+        $(impl Setting for $ty {
+            fn parse(value: &str) -> Result<Self, String> {
+                value.trim().parse().map_err(|err| format!("{}", err))
+            }
+
+            fn to_toml(&self) -> Result<toml::Value, String> {
+                Ok(toml::Value::String(self.to_string()))
+            }
+        })*
+    };
+}
+
+text_setting!(String, Cidr, LogFormat, MismatchPolicy, SamplingMode, AdminToken);
+
+impl<T: Setting> Setting for Vec<T> {
+    /// a comma separated list
+    fn parse(value: &str) -> Result<Self, String> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(T::parse)
+            .collect()
+    }
+
+    fn to_toml(&self) -> Result<toml::Value, String> {
+        self.iter().map(Setting::to_toml).collect::<Result<_, _>>().map(toml::Value::Array)
+    }
+}
+
+/// a value of the configuration file, as it would be written in the environment
+fn toml_to_string(value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        toml::Value::Array(items) => {
+            let items = items.iter().map(toml_to_string).collect::<Result<Vec<_>, _>>()?;
+            Ok(items.join(","))
+        }
+        _ => Err(format!("{} is not a string, an integer, a boolean or an array", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_override_the_configuration_file() {
+        let path = std::env::temp_dir().join(format!("rusty_coin_dns_{}.toml", std::process::id()));
+        fs::write(&path, "node_ttl_secs = 90\nprobe_enabled = true\ntrusted_proxies = [\"10.0.0.0/8\"]\n").unwrap();
+        let args = |flags: &[&str]| {
+            let mut args = vec!["--config".to_string(), path.display().to_string()];
+            args.extend(flags.iter().map(|flag| flag.to_string()));
+            Config::load(args, Vec::new())
+        };
+
+        let config = match args(&["--node-ttl-secs", "120", "--max_query_count=8"]).unwrap() {
+            Command::Run(config) => config,
+            command => panic!("unexpected {:?}", command),
+        };
+        assert_eq!(config.node_ttl_secs, 120);
+        assert_eq!(config.max_query_count, 8);
+        assert!(config.probe_enabled);
+        assert_eq!(config.trusted_proxies, vec!["10.0.0.0/8".parse().unwrap()]);
+        assert!(matches!(args(&["--check-config"]), Ok(Command::CheckConfig(_))));
+
+        assert!(args(&["--node-ttl-secs", "soon"]).unwrap_err().contains("--node-ttl-secs"));
+        assert!(args(&["--no-such-setting", "1"]).unwrap_err().contains("unknown setting"));
+        assert!(args(&["--http-bind-address", "localhost"]).unwrap_err().contains("http_bind_address"));
+        assert!(args(&["--legacy-api-sunset", "2026-03-01"]).unwrap_err().contains("legacy_api_sunset"));
+        assert!(args(&["--legacy-api-sunset", "Sun, 01 Mar 2026 00:00:00 GMT"]).is_ok());
+        assert!(args(&["--admin-tokens", "alice:short"]).unwrap_err().contains("--admin-tokens"));
+        let duplicate = "alice:0123456789abcdef,alice:fedcba9876543210";
+        assert!(args(&["--admin-tokens", duplicate]).unwrap_err().contains("listed twice"));
+        let config = match args(&["--admin-tokens", "alice:0123456789abcdef,bob:fedcba9876543210"]).unwrap() {
+            Command::Run(config) => config,
+            command => panic!("unexpected {:?}", command),
+        };
+        // the tokens are never printed
+        let printed = config.to_toml().unwrap().to_string();
+        assert!(printed.contains("alice:<redacted>") && !printed.contains("0123456789abcdef"), "{}", printed);
+        // a TOML integer is signed, a larger value cannot be printed
+        let config = match args(&["--reap-interval-secs", &u64::MAX.to_string()]).unwrap() {
+            Command::Run(config) => config,
+            command => panic!("unexpected {:?}", command),
+        };
+        assert!(config.to_toml().unwrap_err().starts_with("reap_interval_secs: 18446744073709551615"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn environment_overrides_the_file_but_not_the_flags() {
+        let path = std::env::temp_dir().join(format!("rusty_coin_dns_env_{}.toml", std::process::id()));
+        fs::write(&path, "node_ttl_secs = 90\nmax_query_count = 8\n").unwrap();
+        let vars = |vars: &[(&str, &str)]| -> Vec<(String, String)> {
+            vars.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+        };
+        let config = match Config::load(
+            ["--node-ttl-secs".to_string(), "120".to_string()],
+            vars(&[
+                ("RUSTY_COIN_DNS_CONFIG", &path.display().to_string()),
+                ("RUSTY_COIN_DNS_NODE_TTL_SECS", "100"),
+                ("RUSTY_COIN_DNS_MAX_QUERY_COUNT", "4"),
+                ("NODE_TTL_SECS", "1"),
+            ]),
+        )
+        .unwrap()
+        {
+            Command::Run(config) => config,
+            command => panic!("unexpected {:?}", command),
+        };
+        assert_eq!((config.node_ttl_secs, config.max_query_count), (120, 4));
+
+        let invalid = Config::load(Vec::new(), vars(&[("RUSTY_COIN_DNS_NODE_TTL_SECS", "soon")]));
+        assert!(invalid.unwrap_err().contains("RUSTY_COIN_DNS_NODE_TTL_SECS"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn networks_override_the_global_settings() {
+        let path = std::env::temp_dir().join(format!("rusty_coin_dns_networks_{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "networks = [\"mainnet\", \"testnet\", \"regtest\"]\nnode_ttl_secs = 90\n\
+             [network.testnet]\nnode_ttl_secs = 30\n\
+             [network.regtest]\nallow_private_addresses = true\nstorage_dir = \"/tmp/regtest\"\n",
+        )
+        .unwrap();
+        let args = |flags: &[&str]| {
+            let mut args = vec!["--config".to_string(), path.display().to_string()];
+            args.extend(flags.iter().map(|flag| flag.to_string()));
+            Config::load(args, Vec::new())
+        };
+
+        let config = match args(&["--network.testnet.max-query-count", "4"]).unwrap() {
+            Command::Run(config) => config,
+            command => panic!("unexpected {:?}", command),
+        };
+        let mainnet = config.network("mainnet").unwrap();
+        let testnet = config.network("testnet").unwrap();
+        let regtest = config.network("regtest").unwrap();
+        assert_eq!((mainnet.node_ttl_secs, testnet.node_ttl_secs, regtest.node_ttl_secs), (90, 30, 90));
+        assert_eq!((mainnet.max_query_count, testnet.max_query_count), (32, 4));
+        assert!(regtest.allow_private_addresses && !testnet.allow_private_addresses);
+        // the default network keeps the storage directory, the others get their own
+        assert_eq!(mainnet.storage_dir, "data");
+        assert_eq!(testnet.storage_dir, Path::new("data").join("testnet").display().to_string());
+        assert_eq!(regtest.storage_dir, "/tmp/regtest");
+        let printed = config.to_toml().unwrap();
+        assert_eq!(printed["network"]["testnet"]["max_query_count"].as_integer(), Some(4));
+
+        assert!(args(&["--network.signet.node-ttl-secs", "1"]).unwrap_err().contains("network.signet"));
+        assert!(args(&["--network.testnet.http-workers", "1"]).unwrap_err().contains("per network"));
+        assert!(args(&["--network.testnet.node-ttl-secs", "0"]).unwrap_err().contains("network.testnet.node_ttl_secs"));
+        assert!(args(&["--networks", "mainnet,query"]).unwrap_err().contains("endpoint"));
+        assert!(args(&["--networks", "mainnet,v1"]).unwrap_err().contains("endpoint"));
+        fs::remove_file(&path).unwrap();
+    }
+}
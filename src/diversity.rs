@@ -0,0 +1,243 @@
+//! network diversity of the registered nodes, against eclipse attacks
+//! a host controlling a whole subnet could otherwise register many nodes from it
+//! and get a proportional share of the bootstrap traffic, so:
+//! - the number of nodes registered per subnet (an IPv4 /16 or an IPv6 /32 by default) is capped
+//! - a multi-node query spreads its picks across as many subnets as possible
+//!
+//! with an ASN mapping file, the addresses it covers are grouped by autonomous system instead
+//! of by subnet, one `<CIDR> <ASN>` pair per line, e.g.
+//! ```text
+//! # comments and blank lines are ignored
+//! 93.184.216.0/24 AS15133
+//! 2606:2800::/32 15133
+//! ```
+//! the most specific range containing an address wins
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use rand::prelude::SliceRandom;
+use crate::api::ApiError;
+use crate::cidr::Cidr;
+use crate::config::Config;
+use crate::node::{Node, NodeAddr};
+
+/// the group an address belongs to for the diversity limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Group {
+    Subnet(Cidr),
+    Asn(u32),
+}
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Group::Subnet(subnet) => write!(f, "subnet {}", subnet),
+            Group::Asn(asn) => write!(f, "AS{}", asn),
+        }
+    }
+}
+
+/// how the nodes of a multi-node query are picked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamplingMode {
+    /// uniformly among all the active nodes
+    Uniform,
+    /// spread across the groups, at most one node per group until every group had a pick
+    Diverse,
+}
+
+impl std::str::FromStr for SamplingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "uniform" => Ok(SamplingMode::Uniform),
+            "diverse" => Ok(SamplingMode::Diverse),
+            _ => Err(format!("{:?} is not one of uniform, diverse", s)),
+        }
+    }
+}
+
+impl fmt::Display for SamplingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SamplingMode::Uniform => write!(f, "uniform"),
+            SamplingMode::Diverse => write!(f, "diverse"),
+        }
+    }
+}
+
+/// groups the addresses and enforces the per-group caps
+pub struct Diversity {
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
+    /// maximum number of nodes per group of an IPv4/IPv6 address, 0 for no limit
+    max_ipv4_nodes: usize,
+    max_ipv6_nodes: usize,
+    /// the ranges of the ASN mapping file, most specific first
+    asn_ranges: Vec<(Cidr, u32)>,
+}
+
+impl Diversity {
+    /// the limits of the configuration, loading the ASN mapping file if there is one
+    pub fn from_config(config: &Config) -> io::Result<Diversity> {
+        let asn_ranges = if config.asn_map_file.is_empty() {
+            Vec::new()
+        } else {
+            parse_asn_map(&fs::read_to_string(&config.asn_map_file)?)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+        };
+        Ok(Diversity {
+            ipv4_prefix_len: config.ipv4_subnet_prefix_len,
+            ipv6_prefix_len: config.ipv6_subnet_prefix_len,
+            max_ipv4_nodes: config.max_nodes_per_ipv4_subnet,
+            max_ipv6_nodes: config.max_nodes_per_ipv6_subnet,
+            asn_ranges,
+        })
+    }
+
+    /// the group of `ip`: its autonomous system if it is in the ASN mapping, its subnet otherwise
+    pub fn group(&self, ip: IpAddr) -> Group {
+        if let Some((_, asn)) = self.asn_ranges.iter().find(|(range, _)| range.contains(ip)) {
+            return Group::Asn(*asn);
+        }
+        let prefix_len = match ip {
+            IpAddr::V4(_) => self.ipv4_prefix_len,
+            IpAddr::V6(_) => self.ipv6_prefix_len,
+        };
+        Group::Subnet(Cidr::new(ip, prefix_len))
+    }
+
+    /// the group a node is sampled in, the group of its first address
+    fn node_group(&self, node: &Node) -> Option<Group> {
+        node.ip_addresses().first().map(|ip| self.group(*ip))
+    }
+
+    /// check that registering `address` keeps every group of its addresses within its cap,
+    /// the node already registered at `address` (if any) and the inactive nodes
+    /// do not count against the caps
+    pub fn check(&self, address: &NodeAddr, nodes: &[Node]) -> Result<(), ApiError> {
+        let others: Vec<&Node> = nodes
+            .iter()
+            .filter(|node| !address.matches(node) && node.inactive_since.is_none())
+            .collect();
+        let ipv4 = address.ipv4_address.map(|ip| (IpAddr::V4(ip), self.max_ipv4_nodes));
+        let ipv6 = address.ipv6_address.map(|ip| (IpAddr::V6(ip), self.max_ipv6_nodes));
+        for (ip, max) in ipv4.into_iter().chain(ipv6) {
+            if max == 0 {
+                continue;
+            }
+            let group = self.group(ip);
+            let count = others
+                .iter()
+                .filter(|node| node.ip_addresses().iter().any(|ip| self.group(*ip) == group))
+                .count();
+            if count >= max {
+                return Err(ApiError::GroupFull(format!(
+                    "{} already has {} registered node(s), the maximum",
+                    group, count
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// randomly pick up to `count` distinct nodes among `candidates`,
+    /// spread across as many groups as possible
+    pub fn sample(&self, mut candidates: Vec<Node>, count: usize) -> Vec<Node> {
+        candidates.shuffle(&mut rand::thread_rng());
+
+        // bucket the shuffled candidates by group, the buckets in the order of their first node,
+        // then take one node of every bucket in turn
+        let mut buckets: Vec<VecDeque<Node>> = Vec::new();
+        let mut bucket_of: HashMap<Option<Group>, usize> = HashMap::new();
+        for node in candidates {
+            let index = *bucket_of.entry(self.node_group(&node)).or_insert_with(|| {
+                buckets.push(VecDeque::new());
+                buckets.len() - 1
+            });
+            buckets[index].push_back(node);
+        }
+        let mut picked = Vec::new();
+        while picked.len() < count && !buckets.is_empty() {
+            for bucket in buckets.iter_mut() {
+                if picked.len() == count {
+                    break;
+                }
+                picked.extend(bucket.pop_front());
+            }
+            buckets.retain(|bucket| !bucket.is_empty());
+        }
+        picked
+    }
+}
+
+/// parse the lines of an ASN mapping file
+fn parse_asn_map(contents: &str) -> Result<Vec<(Cidr, u32)>, String> {
+    let mut ranges = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (range, asn) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(range), Some(asn), None) => (range, asn),
+            _ => return Err(format!("line {}: expected `<CIDR> <ASN>`", number + 1)),
+        };
+        let range: Cidr = range.parse().map_err(|err| format!("line {}: {}", number + 1, err))?;
+        let digits = asn.strip_prefix("AS").or_else(|| asn.strip_prefix("as")).unwrap_or(asn);
+        let asn: u32 = digits
+            .parse()
+            .map_err(|_| format!("line {}: {:?} is not an ASN", number + 1, asn))?;
+        ranges.push((range, asn));
+    }
+    ranges.sort_by_key(|(range, _)| std::cmp::Reverse(range.prefix_len()));
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::test_node;
+
+    #[test]
+    fn diverse_sample_takes_one_node_per_subnet_first() {
+        let diversity = Diversity::from_config(&Config::default()).unwrap();
+        // ten nodes in 93.184.0.0/16, one in each of two other subnets
+        let mut candidates: Vec<Node> = (1..=10).map(|i| test_node(&format!("93.184.0.{}", i), 8333, 0)).collect();
+        candidates.push(test_node("198.41.0.4", 8333, 0));
+        candidates.push(test_node("8.8.8.8", 8333, 0));
+
+        for _ in 0..20 {
+            let picked = diversity.sample(candidates.clone(), 3);
+            let mut groups: Vec<Group> = picked
+                .iter()
+                .map(|node| diversity.group(node.ip_addresses()[0]))
+                .collect();
+            groups.sort_by_key(|group| group.to_string());
+            groups.dedup();
+            assert_eq!(groups.len(), 3);
+        }
+        assert_eq!(diversity.sample(candidates, 100).len(), 12);
+    }
+
+    #[test]
+    fn asn_map_groups_by_the_most_specific_range() {
+        let ranges = parse_asn_map("# test\n93.184.0.0/16 AS1\n\n93.184.216.0/24 2 # nested\n").unwrap();
+        let diversity = Diversity {
+            asn_ranges: ranges,
+            ..Diversity::from_config(&Config::default()).unwrap()
+        };
+        assert_eq!(diversity.group("93.184.216.34".parse().unwrap()), Group::Asn(2));
+        assert_eq!(diversity.group("93.184.1.1".parse().unwrap()), Group::Asn(1));
+        assert_eq!(
+            diversity.group("198.41.0.4".parse().unwrap()),
+            Group::Subnet("198.41.0.0/16".parse().unwrap())
+        );
+        assert!(parse_asn_map("93.184.0.0/16").is_err());
+    }
+}
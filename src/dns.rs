@@ -0,0 +1,425 @@
+//! DNS seeding over the DNS wire protocol (RFC 1035)
+//! the active nodes that back `GET /query` are also served as A and AAAA records
+//! of the configured seed domain, so that a node can bootstrap with e.g.
+//! `dig @127.0.0.1 -p 5353 seed.rustycoin.example A`
+//! - every answer is a random subset of the active nodes listening on the default P2P port
+//!   of the network, since the records carry no port
+//! - UDP answers are limited to 512 bytes, an answer that does not fit is truncated
+//!   and flagged with TC, so that the resolver retries over TCP
+//! - TCP messages are prefixed with their length as a 2-byte big-endian integer
+//! - queries for any other name are answered with NXDOMAIN
+//! - malformed queries are answered with FORMERR, or dropped if even the header is unreadable
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::rt::net::{TcpListener, TcpStream, UdpSocket};
+use rand::prelude::SliceRandom;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const HEADER_LEN: usize = 12;
+/// maximum size of a DNS message over UDP without EDNS
+pub const UDP_MAX_LEN: usize = 512;
+/// maximum size of a DNS message over TCP
+pub const TCP_MAX_LEN: usize = u16::MAX as usize;
+/// a TCP connection is closed if no complete query arrives within this time
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// how long the TCP listener waits before accepting again after an error
+const ACCEPT_ERROR_PAUSE: Duration = Duration::from_millis(100);
+/// maximum length of a domain name in wire format
+const MAX_NAME_LEN: usize = 255;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+
+const RCODE_NOERROR: u16 = 0;
+const RCODE_FORMERR: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+
+/// provides the addresses of the nodes that are currently active in the network
+pub type PeerSource = Arc<dyn Fn() -> Vec<IpAddr> + Send + Sync>;
+
+/// the seed domain served by the DNS listener
+pub struct SeedZone {
+    /// the seed domain, lowercase and without the trailing dot
+    domain: String,
+    /// TTL of the A and AAAA records, in seconds
+    ttl: u32,
+    /// maximum number of records in an answer
+    max_answers: usize,
+    peers: PeerSource,
+}
+
+/// the question section of a query
+struct Question<'a> {
+    /// the queried name, lowercase and without the trailing dot
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    /// the question exactly as it appeared in the query, echoed back in the response
+    raw: &'a [u8],
+}
+
+impl SeedZone {
+    pub fn new(domain: &str, ttl: u32, max_answers: usize, peers: PeerSource) -> SeedZone {
+        SeedZone {
+            domain: domain.trim_end_matches('.').to_ascii_lowercase(),
+            ttl,
+            max_answers,
+            peers,
+        }
+    }
+
+    /// build the response to a query packet, at most `max_len` bytes long
+    /// return `None` if the packet should be dropped without a response
+    pub fn answer(&self, query: &[u8], max_len: usize) -> Option<Vec<u8>> {
+        if query.len() < HEADER_LEN {
+            return None;
+        }
+        let id = read_u16(query, 0);
+        let flags = read_u16(query, 2);
+        if flags & FLAG_QR != 0 {
+            // a response, never answer it to avoid loops
+            return None;
+        }
+        // keep the opcode and the recursion desired bit of the query
+        let flags = FLAG_QR | FLAG_AA | (flags & 0x7800) | (flags & FLAG_RD);
+
+        if (flags >> 11) & 0xF != 0 {
+            // only standard queries are supported
+            return Some(header(id, flags | RCODE_NOTIMP, 0, 0));
+        }
+        if read_u16(query, 4) != 1 {
+            return Some(header(id, flags | RCODE_FORMERR, 0, 0));
+        }
+        let question = match parse_question(&query[HEADER_LEN..]) {
+            Some(question) => question,
+            None => return Some(header(id, flags | RCODE_FORMERR, 0, 0)),
+        };
+
+        if question.name != self.domain {
+            let mut response = header(id, flags | RCODE_NXDOMAIN, 1, 0);
+            response.extend_from_slice(question.raw);
+            return Some(response);
+        }
+
+        let addresses = if question.qclass == CLASS_IN || question.qclass == CLASS_ANY {
+            self.sample(question.qtype)
+        } else {
+            Vec::new()
+        };
+
+        let mut response = header(id, flags | RCODE_NOERROR, 1, 0);
+        response.extend_from_slice(question.raw);
+        let mut count: u16 = 0;
+        for address in addresses {
+            let record = self.record(address);
+            if response.len() + record.len() > max_len {
+                let flags = read_u16(&response, 2) | FLAG_TC;
+                response[2..4].copy_from_slice(&flags.to_be_bytes());
+                break;
+            }
+            response.extend_from_slice(&record);
+            count += 1;
+        }
+        response[6..8].copy_from_slice(&count.to_be_bytes());
+
+        Some(response)
+    }
+
+    /// randomly pick the addresses answering a query of type `qtype`
+    fn sample(&self, qtype: u16) -> Vec<IpAddr> {
+        let mut addresses: Vec<IpAddr> = (self.peers)()
+            .into_iter()
+            .filter(|address| match address {
+                IpAddr::V4(_) => qtype == TYPE_A || qtype == TYPE_ANY,
+                IpAddr::V6(_) => qtype == TYPE_AAAA || qtype == TYPE_ANY,
+            })
+            .collect();
+        addresses.shuffle(&mut rand::thread_rng());
+        addresses.truncate(self.max_answers);
+        addresses
+    }
+
+    /// encode an A or AAAA record of the seed domain
+    fn record(&self, address: IpAddr) -> Vec<u8> {
+        // the name is a pointer to the question, which always starts right after the header
+        let mut record = vec![0xC0, HEADER_LEN as u8];
+        let (rtype, rdata) = match address {
+            IpAddr::V4(address) => (TYPE_A, address.octets().to_vec()),
+            IpAddr::V6(address) => (TYPE_AAAA, address.octets().to_vec()),
+        };
+        record.extend_from_slice(&rtype.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&self.ttl.to_be_bytes());
+        record.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        record.extend_from_slice(&rdata);
+        record
+    }
+}
+
+/// answer the queries arriving on a UDP socket, forever
+pub async fn serve_udp(socket: UdpSocket, zone: Arc<SeedZone>) -> io::Result<()> {
+    // queries are small, but EDNS clients may send more than 512 bytes
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                // e.g. an ICMP port unreachable from a previous answer, keep serving
+                tracing::warn!(error = %err, "DNS UDP receive error");
+                continue;
+            }
+        };
+        if let Some(response) = zone.answer(&buf[..len], UDP_MAX_LEN) {
+            if let Err(err) = socket.send_to(&response, peer).await {
+                tracing::debug!(%peer, error = %err, "DNS UDP send error");
+            }
+        }
+    }
+}
+
+/// accept TCP connections and answer the queries arriving on them, forever
+pub async fn serve_tcp(listener: TcpListener, zone: Arc<SeedZone>) -> io::Result<()> {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // e.g. out of file descriptors or a connection aborted before it was accepted,
+                // keep serving, after a pause so that a lasting error does not spin the loop
+                tracing::warn!(error = %err, "DNS TCP accept error");
+                actix_web::rt::time::sleep(ACCEPT_ERROR_PAUSE).await;
+                continue;
+            }
+        };
+        let zone = zone.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(err) = handle_tcp(stream, peer, zone).await {
+                tracing::debug!(%peer, error = %err, "DNS TCP error");
+            }
+        });
+    }
+}
+
+/// answer the length-prefixed queries of a single TCP connection until it is closed
+async fn handle_tcp(mut stream: TcpStream, peer: SocketAddr, zone: Arc<SeedZone>) -> io::Result<()> {
+    loop {
+        let len = match actix_web::rt::time::timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+            Ok(Ok(len)) => len as usize,
+            // the client closed the connection or went idle
+            Ok(Err(err)) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(err)) => return Err(err),
+            Err(_) => return Ok(()),
+        };
+        let mut query = vec![0u8; len];
+        match actix_web::rt::time::timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut query)).await {
+            Ok(result) => result?,
+            Err(_) => return Ok(()),
+        };
+
+        let response = match zone.answer(&query, TCP_MAX_LEN) {
+            Some(response) => response,
+            None => {
+                tracing::debug!(%peer, "DNS TCP dropping malformed query");
+                return Ok(());
+            }
+        };
+        stream.write_u16(response.len() as u16).await?;
+        stream.write_all(&response).await?;
+    }
+}
+
+/// a response header with no answer, authority or additional records
+fn header(id: u16, flags: u16, qdcount: u16, ancount: u16) -> Vec<u8> {
+    let mut header = Vec::with_capacity(UDP_MAX_LEN);
+    header.extend_from_slice(&id.to_be_bytes());
+    header.extend_from_slice(&flags.to_be_bytes());
+    header.extend_from_slice(&qdcount.to_be_bytes());
+    header.extend_from_slice(&ancount.to_be_bytes());
+    header.extend_from_slice(&[0, 0, 0, 0]);
+    header
+}
+
+/// parse the single question that follows the header
+/// return `None` if it is truncated or malformed
+fn parse_question(packet: &[u8]) -> Option<Question<'_>> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut pos = 0;
+    loop {
+        let len = *packet.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // compression pointers (and the reserved label types) are not expected in a question
+        if len & 0xC0 != 0 || pos + len > packet.len() || pos + len > MAX_NAME_LEN {
+            return None;
+        }
+        let label = std::str::from_utf8(&packet[pos..pos + len]).ok()?;
+        labels.push(label.to_ascii_lowercase());
+        pos += len;
+    }
+    if pos + 4 > packet.len() {
+        return None;
+    }
+    Some(Question {
+        name: labels.join("."),
+        qtype: read_u16(packet, pos),
+        qclass: read_u16(packet, pos + 2),
+        raw: &packet[..pos + 4],
+    })
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> u16 {
+    u16::from_be_bytes([packet[pos], packet[pos + 1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const DOMAIN: &str = "seed.rustycoin.example";
+
+    fn zone(peers: Vec<IpAddr>, max_answers: usize) -> Arc<SeedZone> {
+        Arc::new(SeedZone::new(DOMAIN, 60, max_answers, Arc::new(move || peers.clone())))
+    }
+
+    fn v4_peers(count: u8) -> Vec<IpAddr> {
+        (1..=count).map(|i| IpAddr::V4(Ipv4Addr::new(203, 0, 113, i))).collect()
+    }
+
+    /// a hand-built standard query with recursion desired
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = header(id, FLAG_RD, 1, 0);
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    /// the addresses in the answer section of a response to `query`
+    fn answers(query: &[u8], response: &[u8]) -> Vec<IpAddr> {
+        let mut pos = query.len();
+        let mut addresses = Vec::new();
+        for _ in 0..read_u16(response, 6) {
+            let rdlen = read_u16(response, pos + 10) as usize;
+            let rdata = &response[pos + 12..pos + 12 + rdlen];
+            addresses.push(match rdlen {
+                4 => IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+                _ => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(rdata).unwrap())),
+            });
+            pos += 12 + rdlen;
+        }
+        assert_eq!(pos, response.len());
+        addresses
+    }
+
+    #[actix_web::test]
+    async fn udp_a_query_returns_active_nodes() {
+        let peers = vec![
+            IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)),
+            IpAddr::V4(Ipv4Addr::new(203, 0, 113, 2)),
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        ];
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        actix_web::rt::spawn(serve_udp(server, zone(peers.clone(), 10)));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = query(0x1234, "SEED.rustycoin.example", TYPE_A);
+        client.send_to(&request, server_addr).await.unwrap();
+        let mut buf = [0u8; UDP_MAX_LEN];
+        let len = client.recv(&mut buf).await.unwrap();
+        let response = &buf[..len];
+
+        assert_eq!(read_u16(response, 0), 0x1234);
+        let flags = read_u16(response, 2);
+        assert_ne!(flags & FLAG_QR, 0);
+        assert_ne!(flags & FLAG_RD, 0);
+        assert_eq!(flags & FLAG_TC, 0);
+        assert_eq!(flags & 0xF, RCODE_NOERROR);
+        let mut addresses = answers(&request, response);
+        addresses.sort();
+        assert_eq!(addresses, peers[..2].to_vec());
+    }
+
+    #[actix_web::test]
+    async fn udp_unknown_name_is_nxdomain() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        actix_web::rt::spawn(serve_udp(server, zone(v4_peers(3), 10)));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = query(7, "other.rustycoin.example", TYPE_A);
+        client.send_to(&request, server_addr).await.unwrap();
+        let mut buf = [0u8; UDP_MAX_LEN];
+        let len = client.recv(&mut buf).await.unwrap();
+
+        assert_eq!(read_u16(&buf, 2) & 0xF, RCODE_NXDOMAIN);
+        assert_eq!(read_u16(&buf, 6), 0);
+        assert_eq!(&buf[HEADER_LEN..len], &request[HEADER_LEN..]);
+    }
+
+    #[actix_web::test]
+    async fn udp_malformed_query_is_formerr() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        actix_web::rt::spawn(serve_udp(server, zone(v4_peers(3), 10)));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // a garbage packet shorter than a header is dropped, the server keeps serving
+        client.send_to(&[1, 2, 3], server_addr).await.unwrap();
+        // the question is cut off in the middle of the name
+        let mut request = query(9, DOMAIN, TYPE_A);
+        request.truncate(HEADER_LEN + 6);
+        client.send_to(&request, server_addr).await.unwrap();
+        let mut buf = [0u8; UDP_MAX_LEN];
+        let len = client.recv(&mut buf).await.unwrap();
+
+        assert_eq!(len, HEADER_LEN);
+        assert_eq!(read_u16(&buf, 0), 9);
+        assert_eq!(read_u16(&buf, 2) & 0xF, RCODE_FORMERR);
+    }
+
+    #[actix_web::test]
+    async fn truncated_udp_answer_is_complete_over_tcp() {
+        let peers = v4_peers(60);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let zone = zone(peers.clone(), 60);
+        actix_web::rt::spawn(serve_tcp(listener, zone.clone()));
+
+        let request = query(42, DOMAIN, TYPE_A);
+        let udp_response = zone.answer(&request, UDP_MAX_LEN).unwrap();
+        assert!(udp_response.len() <= UDP_MAX_LEN);
+        assert_ne!(read_u16(&udp_response, 2) & FLAG_TC, 0);
+        assert!(answers(&request, &udp_response).len() < peers.len());
+
+        let mut stream = TcpStream::connect(server_addr).await.unwrap();
+        stream.write_u16(request.len() as u16).await.unwrap();
+        stream.write_all(&request).await.unwrap();
+        let len = stream.read_u16().await.unwrap() as usize;
+        let mut response = vec![0u8; len];
+        stream.read_exact(&mut response).await.unwrap();
+
+        assert_eq!(read_u16(&response, 2) & FLAG_TC, 0);
+        let mut addresses = answers(&request, &response);
+        addresses.sort();
+        assert_eq!(addresses, peers);
+    }
+}
@@ -0,0 +1,232 @@
+//! the stream of node events, served by `GET /v1/events` as Server-Sent Events
+//! so that long-running nodes and dashboards learn about their peers without polling `/query`
+//! - an event is sent when a node registers (or registers again after it became inactive),
+//!   deregisters, expires (its TTL and grace period lapsed), is removed by an operator
+//!   or passes or fails its health probes often enough to change its health
+//! - every event has an ID, increasing across all the networks, and a JSON body
+//!   `{"id": 42, "event": "registered", "network": "mainnet", "time": <UNIX timestamp>, "node": {...}}`
+//! - `network` and `family` (`v4`, `v6` or `any`) filter the events
+//! - the last events are kept in a bounded log, a client resuming after event `N`
+//!   (the standard `Last-Event-ID` header, or `last_event_id=N`) gets the events it missed,
+//!   without either it only gets the new events
+//! - if some of the events a client asked for are not in the log anymore (or the server
+//!   restarted since), or if the client is too slow to keep up, a `lost` event tells it
+//!   to query the nodes again
+//! - a comment is sent when there is no event for a while, so that proxies keep the stream open
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use actix_web::web::Bytes;
+use futures_util::stream::{self, Stream};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::node::{Family, Node};
+
+/// what happened to a node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeEventKind {
+    /// a new node registered, or an inactive node registered again
+    Registered,
+    Deregistered,
+    /// the TTL and grace period of the node lapsed, it became inactive
+    Expired,
+    /// an operator removed the node, or banned it, see the `admin` module
+    Removed,
+    /// the node passed a probe after it was unhealthy (or never probed)
+    Healthy,
+    /// the node failed too many probes in a row
+    Unhealthy,
+}
+
+impl NodeEventKind {
+    fn label(&self) -> &'static str {
+        match self {
+            NodeEventKind::Registered => "registered",
+            NodeEventKind::Deregistered => "deregistered",
+            NodeEventKind::Expired => "expired",
+            NodeEventKind::Removed => "removed",
+            NodeEventKind::Healthy => "healthy",
+            NodeEventKind::Unhealthy => "unhealthy",
+        }
+    }
+}
+
+/// an event of the stream
+#[derive(Debug, Serialize)]
+pub struct NodeEvent {
+    pub id: u64,
+    pub event: NodeEventKind,
+    pub network: String,
+    /// the UNIX timestamp (in seconds) of the event
+    pub time: u64,
+    /// the node after the event
+    pub node: Node,
+}
+
+/// the last events, and the subscribers waiting for the next ones
+pub struct EventLog {
+    capacity: usize,
+    /// the retained events, oldest first, and the ID of the next event
+    events: Mutex<(VecDeque<Arc<NodeEvent>>, u64)>,
+    sender: broadcast::Sender<Arc<NodeEvent>>,
+}
+
+/// the events a new subscriber gets
+pub struct Subscription {
+    /// the retained events after the one the subscriber resumes from
+    backlog: VecDeque<Arc<NodeEvent>>,
+    /// whether some of the events the subscriber asked for are lost
+    lost: bool,
+    receiver: broadcast::Receiver<Arc<NodeEvent>>,
+}
+
+impl EventLog {
+    /// keep the last `capacity` events, which is also how far behind a subscriber can fall
+    pub fn new(capacity: usize) -> EventLog {
+        let capacity = capacity.max(1);
+        EventLog {
+            capacity,
+            events: Mutex::new((VecDeque::with_capacity(capacity), 1)),
+            sender: broadcast::channel(capacity).0,
+        }
+    }
+
+    /// record that `kind` happened to `node` of `network` at `now`
+    pub fn publish(&self, network: &str, kind: NodeEventKind, node: &Node, now: u64) {
+        let mut events = self.events.lock().unwrap();
+        let (log, next_id) = &mut *events;
+        let event = Arc::new(NodeEvent {
+            id: *next_id,
+            event: kind,
+            network: network.to_string(),
+            time: now,
+            node: node.clone(),
+        });
+        *next_id += 1;
+        if log.len() == self.capacity {
+            log.pop_front();
+        }
+        log.push_back(event.clone());
+        // the subscribers are registered under the same lock, so that none misses an event
+        // or gets it twice, there may be none
+        let _ = self.sender.send(event);
+    }
+
+    /// subscribe to the events after `last_event_id`, or to the new events only
+    pub fn subscribe(&self, last_event_id: Option<u64>) -> Subscription {
+        let events = self.events.lock().unwrap();
+        let (log, next_id) = &*events;
+        let (backlog, lost) = match last_event_id {
+            None => (VecDeque::new(), false),
+            Some(last_event_id) => {
+                let oldest = log.front().map_or(*next_id, |event| event.id);
+                let backlog = log.iter().filter(|event| event.id > last_event_id).cloned().collect();
+                // an ID from the future was handed out before a restart
+                (backlog, last_event_id.saturating_add(1) < oldest || last_event_id >= *next_id)
+            }
+        };
+        Subscription {
+            backlog,
+            lost,
+            receiver: self.sender.subscribe(),
+        }
+    }
+}
+
+/// the events a subscriber is interested in
+#[derive(Debug, Default)]
+pub struct EventFilter {
+    /// all the networks if `None`
+    pub network: Option<String>,
+    pub family: Family,
+}
+
+impl EventFilter {
+    fn accepts(&self, event: &NodeEvent) -> bool {
+        self.network.as_ref().is_none_or(|network| *network == event.network) && event.node.has_family(self.family)
+    }
+}
+
+/// an event in the format of Server-Sent Events
+fn frame(event: &NodeEvent) -> Bytes {
+    let data = serde_json::to_string(event).unwrap_or_default();
+    Bytes::from(format!("id: {}\nevent: {}\ndata: {}\n\n", event.id, event.event.label(), data))
+}
+
+/// the event telling a subscriber that it missed some events
+fn lost() -> Bytes {
+    Bytes::from_static(b"event: lost\ndata: {\"message\": \"some events were lost, query the nodes again\"}\n\n")
+}
+
+/// the body of the stream of `subscription`, with a comment after `keepalive` without any event
+pub fn stream(
+    subscription: Subscription,
+    filter: EventFilter,
+    keepalive: Duration,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    stream::unfold((subscription, filter), move |(mut subscription, filter)| async move {
+        if subscription.lost {
+            subscription.lost = false;
+            return Some((Ok(lost()), (subscription, filter)));
+        }
+        while let Some(event) = subscription.backlog.pop_front() {
+            if filter.accepts(&event) {
+                return Some((Ok(frame(&event)), (subscription, filter)));
+            }
+        }
+        loop {
+            let chunk = match actix_web::rt::time::timeout(keepalive, subscription.receiver.recv()).await {
+                Err(_) => Bytes::from_static(b": keep-alive\n\n"),
+                Ok(Ok(event)) if filter.accepts(&event) => frame(&event),
+                Ok(Ok(_)) => continue,
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => lost(),
+                Ok(Err(broadcast::error::RecvError::Closed)) => return None,
+            };
+            return Some((Ok(chunk), (subscription, filter)));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::test_node;
+
+    #[test]
+    fn subscribers_resume_from_the_bounded_log() {
+        let log = EventLog::new(3);
+        for port in 1..=5 {
+            log.publish("mainnet", NodeEventKind::Registered, &test_node("93.184.216.34", port, 0), 0);
+        }
+        let ids = |subscription: &Subscription| subscription.backlog.iter().map(|event| event.id).collect::<Vec<_>>();
+
+        // events 1 and 2 were dropped
+        let subscription = log.subscribe(Some(3));
+        assert_eq!((ids(&subscription), subscription.lost), (vec![4, 5], false));
+        let subscription = log.subscribe(Some(2));
+        assert_eq!((ids(&subscription), subscription.lost), (vec![3, 4, 5], false));
+        let subscription = log.subscribe(Some(0));
+        assert_eq!((ids(&subscription), subscription.lost), (vec![3, 4, 5], true));
+        // an ID of a previous run
+        let subscription = log.subscribe(Some(9));
+        assert_eq!((ids(&subscription), subscription.lost), (vec![], true));
+
+        let mut subscription = log.subscribe(None);
+        assert!(ids(&subscription).is_empty());
+        log.publish("testnet", NodeEventKind::Deregistered, &test_node("93.184.216.34", 6, 0), 0);
+        let event = subscription.receiver.try_recv().unwrap();
+        assert_eq!((event.id, event.event), (6, NodeEventKind::Deregistered));
+        let filter = EventFilter {
+            network: Some("mainnet".to_string()),
+            family: Family::Any,
+        };
+        assert!(!filter.accepts(&event));
+        let filter = EventFilter {
+            network: None,
+            family: Family::V6,
+        };
+        assert!(!filter.accepts(&event));
+    }
+}
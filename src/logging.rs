@@ -0,0 +1,154 @@
+//! leveled, structured logging
+//! the events are written to stdout as JSON lines or as text with `key=value` fields,
+//! filtered by level (and optionally by module, e.g. `info,rusty_coin_dns::dns=debug`)
+//!
+//! every HTTP request gets an ID, taken from its `X-Request-Id` header if it has a sane one,
+//! or generated otherwise, which is echoed in the `X-Request-Id` response header
+//! and attached to every event logged while handling the request
+
+use std::fmt;
+use std::future::{ready, Ready};
+use std::str::FromStr;
+use std::time::Instant;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use futures_util::future::LocalBoxFuture;
+use rand::Rng;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+use crate::config::Config;
+
+/// the header carrying the ID of a request
+const REQUEST_ID_HEADER: &str = "x-request-id";
+/// maximum length of a request ID taken from a request
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// the format of the log events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// one JSON object per line
+    Json,
+    /// one line of text per event, with the fields as `key=value`
+    Text,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "json" => Ok(LogFormat::Json),
+            "text" => Ok(LogFormat::Text),
+            _ => Err(format!("{:?} is not one of json, text", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Json => write!(f, "json"),
+            LogFormat::Text => write!(f, "text"),
+        }
+    }
+}
+
+/// check a log level filter, e.g. `info` or `warn,rusty_coin_dns::store=debug`
+pub fn check_filter(filter: &str) -> Result<(), String> {
+    EnvFilter::try_new(filter).map(|_| ()).map_err(|err| err.to_string())
+}
+
+/// install the logger of the configuration, once on startup
+pub fn init(config: &Config) {
+    let filter = EnvFilter::try_new(&config.log_level).unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.log_format {
+        LogFormat::Json => builder.json().flatten_event(true).with_span_list(false).init(),
+        LogFormat::Text => builder.init(),
+    }
+}
+
+/// the middleware giving every request an ID and logging its outcome
+pub struct RequestTrace;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTrace
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestTraceMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestTraceMiddleware { service }))
+    }
+}
+
+pub struct RequestTraceMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTraceMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| is_valid_request_id(value))
+            .map(str::to_string)
+            .unwrap_or_else(new_request_id);
+        let span = tracing::info_span!(
+            "request",
+            request_id = %request_id,
+            method = %req.method(),
+            path = %req.path(),
+        );
+        let started = Instant::now();
+        let response = span.in_scope(|| self.service.call(req));
+
+        Box::pin(
+            async move {
+                let mut response = response.await?;
+                let status = response.status();
+                let latency_ms = started.elapsed().as_millis() as u64;
+                if status.is_server_error() {
+                    tracing::error!(status = status.as_u16(), latency_ms, "request failed");
+                } else {
+                    tracing::info!(status = status.as_u16(), latency_ms, "request handled");
+                }
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    response
+                        .headers_mut()
+                        .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+                Ok(response)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// whether a request ID sent by a client can be used as is
+fn is_valid_request_id(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LEN
+        && value.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
+
+/// a random request ID of 16 hex digits
+fn new_request_id() -> String {
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
@@ -7,137 +7,2107 @@
 //! - query the existing active nodes in the network
 //!
 //! API:
+//! - every response is a JSON object, an error is `{"error": "<code>", "message": "<reason>"}`,
+//!   see the `api` module for the codes, an unknown endpoint gets 404 Not Found and `not_found`
 //! - GET /
 //!     - the index page of the DNS server, test the server is running
-//!     - return "Hello World!"
+//!     - return `{"message": "Hello World!"}`
 //! - POST /register
 //!    - register a node with the DNS server
-//!    - require `ipv4_address: String` and `port: u16` in the request body
-//!    - return "register node <IP address>:<port> successfully"
-//!    - otherwise, return 400 Bad Request
+//!    - require `ipv4_address: String`, `ipv6_address: String` or both, and `port: u16`
+//!      in the request body, a dual-stack node registers both addresses at once
+//!    - registering the same port and either IP address again refreshes the existing
+//!      registration, an address family left out of the refresh keeps its registered address
+//!    - return 201 Created with
+//!      `{"message": "register node <IP address>:<port> successfully", "ttl": <seconds>}`,
+//!      or 200 OK with `{"message": "refresh node <IP address>:<port> successfully", ...}`
+//!      if the node was already registered
+//!    - the node must send a heartbeat within `ttl` seconds, otherwise it goes stale,
+//!      and inactive after the grace period (see the `node` module)
+//!    - registering an inactive node makes it active again, as a new node
+//!    - optionally describe the node with `protocol_version: u32`, `services: u64` (a bit field),
+//!      `user_agent: String` (printable ASCII, at most 256 characters) and `best_height: u64`,
+//!      which are returned by `/query` (a refresh replaces them)
+//!    - the addresses must be publicly routable and `port` must not be 0,
+//!      loopback, private, shared (carrier-grade NAT), link-local, multicast, documentation,
+//!      benchmarking and reserved addresses are only accepted in private network mode
+//!    - otherwise, return 400 Bad Request with
+//!      `{"error": "invalid_field", "field": "<field>", "message": "<reason>"}`,
+//!      or `{"error": "invalid_body", "message": "<reason>"}` if the body cannot be parsed
+//!    - the addresses may be omitted, the address the request came from is registered then
+//!      (the TCP peer, or `X-Forwarded-For` behind a trusted proxy)
+//!    - depending on the address mismatch policy, addresses in the body that differ from the
+//!      address the request came from are allowed, overridden or rejected (400 Bad Request),
+//!      the same policy applies to `/heartbeat` and `/deregister`
+//!    - optionally signed with the Ed25519 key of the node (`public_key`, `timestamp`, `nonce`
+//!      and `signature` in the request body), which binds the node to that key,
+//!      see the `signature` module
+//!    - return 401 Unauthorized if the signature is invalid or replayed (or missing while
+//!      signatures are required), 403 Forbidden if the node is bound to another key
+//!    - if proof of work is enabled, a new node must also post `pow_challenge` and
+//!      `pow_solution`, the solution of a challenge of `/register/challenge`,
+//!      otherwise return 403 Forbidden with `{"error": "invalid_proof_of_work", ...}`
+//!    - return 403 Forbidden with `{"error": "banned", ...}` if the address or the public key
+//!      of the node is banned, the same applies to `/heartbeat`, see the `admin` module
+//! - GET /register/challenge
+//!    - return a proof-of-work challenge for a new registration, see the `pow` module
+//!    - `{"challenge": "<hex>", "difficulty": <bits>, "expires_in": <seconds>}`
+//!    - the difficulty grows with the number of new nodes registered in the last minute
+//!    - return 404 Not Found with `pow_disabled` if proof of work is not enabled,
+//!      or 503 Service Unavailable with `too_many_challenges` if too many are pending
+//!    - return 403 Forbidden with `{"error": "group_full", ...}` if a new address would exceed
+//!      the cap of nodes per subnet (or autonomous system), see the `diversity` module
+//! - POST /heartbeat
+//!    - tell the DNS server that a registered node is still alive
+//!    - require `ipv4_address: String` or `ipv6_address: String`, and `port: u16`
+//!      in the request body, either address of a dual-stack node identifies it
+//!    - return `{"message": "heartbeat of node <IP address>:<port> received", "ttl": <seconds>}`
+//!    - a stale node becomes active again
+//!    - `best_height` (optional) updates the best block height of the node
+//!    - return 404 Not Found with `node_not_found` if the node is not registered (or is inactive)
+//!    - return 400 Bad Request as for `/register` if the address cannot be parsed
+//!    - must be signed as for `/register` if the node is bound to a public key
 //! - POST /deregister
 //!    - deregister a node from the DNS server
-//!    - require `ipv4_address: String` and `port: u16` in the request body
-//!    - return "deregister node <IP address>:<port> successfully"
-//!    - otherwise, return 400 Bad Request
-//! - GET /query
+//!    - require `ipv4_address: String` or `ipv6_address: String`, and `port: u16`
+//!      in the request body, either address of a dual-stack node identifies it
+//!    - return `{"message": "deregister node <IP address>:<port> successfully"}`
+//!    - the node becomes inactive, it is kept for the retention period before it is purged
+//!    - return 400 Bad Request as for `/register` if the address cannot be parsed
+//!    - must be signed as for `/register` if the node is bound to a public key
+//! - GET /query?count=<N>&family=<v4|v6|any>&min_version=<V>&services=<bits>&min_height=<H>
 //!    - query the existing active nodes in the network
-//!    - randomly poll a node from the list of active nodes
-//!    - and return its IP address & port
-//!    - return "no active nodes in the network" if there is no active nodes
+//!    - randomly poll up to `count` distinct nodes from the list of active nodes
+//!    - and return `{"nodes": [...]}`, their IP addresses & ports,
+//!      together with the results of their health probes
+//!    - `count` (optional, 1 by default) is capped by the server-side maximum,
+//!      0 is rejected with 400 Bad Request and `invalid_query`
+//!    - `family` (optional, `any` by default) only polls the nodes with an address of that family
+//!    - `min_version`, `services` and `min_height` (optional) only poll the nodes with at least
+//!      that protocol version, all of those service bits and at least that best block height,
+//!      a node that did not report its version (or height) is left out by the filter
+//!    - the nodes are spread across as many subnets as possible, unless uniform sampling
+//!      is configured
+//!    - a node is active if its TTL has not lapsed and, if probing is enabled,
+//!      it passed its recent TCP connect probes
+//!    - if there is no active nodes, the nodes that became stale or inactive within the
+//!      fallback window are polled instead, with an `X-Node-Pool: inactive` response header
+//!    - return 404 Not Found with `no_nodes` if there is no such nodes either
+//! - GET /nodes/inactive
+//!    - list the stale and inactive nodes that have not been purged yet
+//!    - return `{"nodes": [...]}`, with their `state` and `inactive_since`
+//!      (a stale node has none)
+//!
+//! Versions:
+//! - the node endpoints above are served under `/v1`, e.g. `/v1/query`
+//! - the routes without a version are the deprecated legacy API, served from the same nodes
+//!   with the bodies of the nodes deployed before `/v1`: `/register` and `/deregister` return
+//!   plain text, `/query` returns a single node (or the JSON string
+//!   `"no active nodes in the network"`), a bare JSON array if it has a `count`,
+//!   their responses carry `Deprecation`, `Link` and (once decided) `Sunset` headers,
+//!   see the `api` module
+//!
+//! Networks:
+//! - the node endpoints above are also served under `/v1/<network>`, e.g. `/v1/testnet/query`,
+//!   for every configured network (mainnet, testnet, regtest...), each with its own nodes
+//!   and settings, the endpoints without a network serve the default network,
+//!   an unknown network gets 404 Not Found, see the `network` module
+//!
+//! Logging:
+//! - leveled, structured events (JSON or text), with the ID of the request they belong to,
+//!   see the `logging` module
+//!
+//! Configuration:
+//! - every setting can be set in a TOML file, an environment variable or a command line flag,
+//!   see the `config` module and `--help`
+//! - `--check-config` validates the configuration and prints the effective settings
+//!
+//! Rate limiting:
+//! - if enabled, `/register`, `/heartbeat`, `/deregister` and `/query` are rate-limited per client,
+//!   a client over budget gets 429 Too Many Requests with a `Retry-After` header,
+//!   see the `ratelimit` module
+//!
+//! - GET /v1/events?network=<network>&family=<v4|v6|any>&last_event_id=<ID>
+//!    - a stream of Server-Sent Events telling when nodes register, deregister, expire or change
+//!      health, optionally only of a network and an address family
+//!    - resumes after the `Last-Event-ID` header (or `last_event_id`) from a bounded log of the
+//!      last events, see the `events` module
+//! - GET /metrics
+//!    - the metrics of the DNS server in the Prometheus text format, see the `metrics` module
+//! - GET /openapi.json
+//!    - the OpenAPI 3 description of the `/v1` API, see the `openapi` module
+//!
+//! Admin:
+//! - if operator tokens are configured, `/admin` serves the operators with a token:
+//!   paginated listing and search of the nodes, edits of their metadata and state, forced removal,
+//!   bans of addresses, CIDR ranges and public keys, and an audit log of their actions,
+//!   see the `admin` module
+//!
+//! DNS:
+//! - if enabled, the active nodes are also served as A and AAAA records of the seed domain
+//!   over UDP and TCP, only those listening on the default P2P port (`dns_node_port`) since
+//!   a record carries no port, see the `dns` module
+//!
+//! Health probing:
+//! - if enabled, every registered node is periodically probed with a TCP connect,
+//!   see the `probe` module
+//!
+//! Storage:
+//! - the nodes are kept in a `NodeStore`, see the `store` module
+//! - if the storage is enabled, every registration and deregistration is written to a log
+//!   on disk before it is answered, and the nodes are loaded again on startup,
+//!   see the `persistence` module, otherwise the nodes are only kept in memory
 
+mod admin;
+mod api;
+mod cidr;
+mod client;
+mod config;
+mod diversity;
+mod dns;
+mod events;
+mod logging;
+mod metrics;
+mod network;
+mod node;
+mod openapi;
+mod persistence;
+mod pow;
+mod probe;
+mod ratelimit;
+mod signature;
+mod store;
+mod validation;
 
-use std::sync::{Arc, Mutex};
-use actix_web::{App, HttpServer, Responder, get, HttpResponse, post, web};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::{App, HttpRequest, HttpServer, Responder, get, guard, HttpResponse, post, web};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use once_cell::sync::Lazy;
-use rand::prelude::SliceRandom;
+use tracing::Instrument;
+use crate::admin::{Admin, BanList};
+use crate::api::{ApiError, ApiVersion, Deprecated, LeaseResponse, MessageResponse, NodesResponse, LEGACY_NO_NODES};
+use crate::config::{Command, Config};
+use crate::diversity::{Diversity, SamplingMode};
+use crate::events::{EventFilter, EventLog, NodeEventKind};
+use crate::logging::RequestTrace;
+use crate::metrics::{Event, Metrics, RecordMetrics};
+use crate::network::{Network, NetworkState, Networks};
+use crate::node::{now, Family, Metadata, Node, NodeAddr, NodeState};
+use crate::probe::Health;
+use crate::ratelimit::{RateLimit, RateLimiter};
+use crate::store::{FileStore, MemoryStore, NodeStore};
+use crate::signature::{Action, ReplayGuard};
+use crate::validation::NodeRequest;
 
 #[get("/")]
 async fn index() -> impl Responder {
     // the index page of the DNS server
     // test the server is running
     // return "Hello World!"
-    HttpResponse::Ok().body("Hello World!")
+    HttpResponse::Ok().json(MessageResponse {
+        message: "Hello World!".to_string(),
+    })
+}
+
+/// answer the requests that match no route
+async fn not_found() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::NotFound)
 }
 
 #[post("/deregister")]
-async fn deregister(info: web::Json<Node>) -> impl Responder {
+async fn deregister(
+    req: HttpRequest,
+    info: web::Json<NodeRequest>,
+    network: Network,
+    version: ApiVersion,
+    replay_guard: web::Data<ReplayGuard>,
+    metrics: web::Data<Metrics>,
+    events: web::Data<EventLog>,
+) -> Result<HttpResponse, ApiError> {
     // deregister a node from the DNS server
-    let mut nodes = NODES.lock().unwrap();
-
-    nodes.retain(|node| {
-        node.ipv4_address != info.ipv4_address || node.port != info.port
-    });
-
-    println!("nodes: {:?}", nodes);
+    let config = &network.config;
+    let store = &network.store;
+    let observed = client::client_ip(&req, &config.trusted_proxies);
+    let address = info.resolve(observed, config.address_mismatch_policy)?;
+    // nodes bound to a public key can only be deregistered with a signature of that key
+    let existing = store.find(&address);
+    signature::authorize(
+        Action::Deregister,
+        &info,
+        &existing,
+        config.require_signatures,
+        &replay_guard,
+        now(),
+    )?;
+    // the node is kept as inactive, so that it can still be handed out if no node is active
+    match store.deactivate(&address, now()) {
+        Ok(deactivated) => {
+            for node in deactivated {
+                tracing::info!(node = %node.addr(), "node deregistered");
+                metrics.record(Event::Deregistered);
+                events.publish(&network.name, NodeEventKind::Deregistered, &node, now());
+            }
+        }
+        Err(err) => {
+            tracing::error!(node = %address, error = %err, "failed to persist the deregistration");
+            return Err(ApiError::Internal("failed to persist the deregistration".to_string()));
+        }
+    }
 
-    HttpResponse::Ok().body(format!("deregister node {}:{} successfully", info.ipv4_address, info.port))
+    let message = format!("deregister node {} successfully", address);
+    Ok(match version {
+        ApiVersion::Legacy => HttpResponse::Ok().body(message),
+        ApiVersion::V1 => HttpResponse::Ok().json(MessageResponse { message }),
+    })
 }
 
 #[post("/register")]
-async fn register(info: web::Json<Node>) -> impl Responder {
+async fn register(
+    req: HttpRequest,
+    info: web::Json<NodeRequest>,
+    network: Network,
+    replay_guard: web::Data<ReplayGuard>,
+    bans: web::Data<BanList>,
+    metrics: web::Data<Metrics>,
+    events: web::Data<EventLog>,
+) -> Result<HttpResponse, ApiError> {
     // register a node with the DNS server
-    let node = info.into_inner();
-    let ipv4_address = node.ipv4_address;
-    let port = node.port;
+    let config = &network.config;
+    let store = &network.store;
+    let observed = client::client_ip(&req, &config.trusted_proxies);
+    let address = info.resolve(observed, config.address_mismatch_policy)?;
+    bans.check(&address, info.public_key.as_deref(), now())?;
+    validation::check_addr(&address, config.allow_private_addresses)?;
+    let metadata = info.metadata()?;
+    // nodes already bound to a public key can only be refreshed with a signature of that key,
+    // every node matching either address is checked since the registration merges them all
+    let existing = store.find(&address);
+    let public_key = signature::authorize(
+        Action::Register,
+        &info,
+        &existing,
+        config.require_signatures,
+        &replay_guard,
+        now(),
+    )?;
     let node = Node {
-        ipv4_address: ipv4_address.clone(),
-        ipv6_address: None,
-        port,
+        ipv4_address: address.ipv4_address,
+        ipv6_address: address.ipv6_address,
+        port: address.port,
+        public_key,
+        last_seen: now(),
+        health: Health::default(),
+        metadata,
+        inactive_since: None,
+    };
+    let probe_address = node.probe_address();
+
+    // the caps and the proof of work are checked under the lock of the store, so that concurrent
+    // registrations from the same subnet cannot all pass the check and exceed the cap together,
+    // and a node is created exactly when it paid for it
+    let mut rejection = None;
+    let mut known = false;
+    let inserted = store.insert_if(node.clone(), &mut |nodes| {
+        // only new (or inactive) nodes pay for their registration, refreshing a node does not add one
+        known = nodes
+            .iter()
+            .any(|node| address.matches(node) && node.state(now(), config) != NodeState::Inactive);
+        let checked = network.diversity.check(&address, nodes).and_then(|()| match config.pow_enabled && !known {
+            true => network.pow_guard.verify(&info, now()),
+            false => Ok(()),
+        });
+        match checked {
+            Ok(()) => true,
+            Err(err) => {
+                rejection = Some(err);
+                false
+            }
+        }
+    });
+    let created = match inserted {
+        Ok(Some(_)) => !known,
+        Ok(None) => {
+            return Err(rejection.unwrap_or_else(|| ApiError::Internal("the registration was rejected".to_string())))
+        }
+        Err(err) => {
+            tracing::error!(node = %address, error = %err, "failed to persist the registration");
+            return Err(ApiError::Internal("failed to persist the registration".to_string()));
+        }
+    };
+
+    // the legacy routes answer every registration with the same text, as before the leases
+    let version = ApiVersion::of(&req);
+    let legacy = || HttpResponse::Ok().body(format!("register node {} successfully", address));
+    if !created {
+        tracing::debug!(node = %address, "node refreshed");
+        metrics.record(Event::Refreshed);
+        return Ok(match version {
+            ApiVersion::Legacy => legacy(),
+            ApiVersion::V1 => HttpResponse::Ok().json(LeaseResponse {
+                message: format!("refresh node {} successfully", address),
+                ttl: config.node_ttl_secs,
+            }),
+        });
+    }
+    network.pow_guard.record_registration(now());
+    tracing::info!(node = %address, "node registered");
+    metrics.record(Event::Registered);
+    events.publish(&network.name, NodeEventKind::Registered, &node, now());
+
+    // probe the new node right away instead of waiting for the next probe round,
+    // so that a reachable node is handed out as soon as possible
+    if let (true, Some(probe_address)) = (config.probe_enabled, probe_address) {
+        let timeout = Duration::from_millis(config.probe_timeout_ms);
+        let network = network.clone();
+        let metrics = metrics.into_inner();
+        let events = events.into_inner();
+        let address = address.clone();
+        actix_web::rt::spawn(async move {
+            let latency = probe::probe(probe_address, timeout).await;
+            metrics.record(if latency.is_some() { Event::ProbeSucceeded } else { Event::ProbeFailed });
+            let NetworkState { name, config, store, .. } = &*network;
+            record_probe(name, config, store.as_ref(), &events, &address, latency);
+        });
+    }
+
+    Ok(match version {
+        ApiVersion::Legacy => legacy(),
+        ApiVersion::V1 => HttpResponse::Created().json(LeaseResponse {
+            message: format!("register node {} successfully", address),
+            ttl: config.node_ttl_secs,
+        }),
+    })
+}
+
+#[get("/register/challenge")]
+async fn register_challenge(network: Network) -> Result<HttpResponse, ApiError> {
+    // hand out a proof-of-work challenge for a new registration
+    if !network.config.pow_enabled {
+        return Err(ApiError::PowDisabled);
+    }
+    match network.pow_guard.issue(now()) {
+        Some(challenge) => Ok(HttpResponse::Ok().json(challenge)),
+        None => Err(ApiError::TooManyChallenges),
+    }
+}
+
+#[post("/heartbeat")]
+async fn heartbeat(
+    req: HttpRequest,
+    info: web::Json<NodeRequest>,
+    network: Network,
+    replay_guard: web::Data<ReplayGuard>,
+    bans: web::Data<BanList>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, ApiError> {
+    // refresh the last-seen timestamp of a registered node
+    let config = &network.config;
+    let store = &network.store;
+    let now = now();
+    let observed = client::client_ip(&req, &config.trusted_proxies);
+    let address = info.resolve(observed, config.address_mismatch_policy)?;
+    bans.check(&address, info.public_key.as_deref(), now)?;
+    let existing = store.find(&address);
+    signature::authorize(
+        Action::Heartbeat,
+        &info,
+        &existing,
+        config.require_signatures,
+        &replay_guard,
+        now,
+    )?;
+
+    // a stale node is revived, an inactive node must register again
+    let alive = existing.iter().any(|node| node.state(now, config) != NodeState::Inactive);
+
+    let update = &mut |node: &mut Node| {
+        node.last_seen = now;
+        if let Some(best_height) = info.best_height {
+            node.metadata.best_height = Some(best_height);
+        }
     };
-    let mut nodes = NODES.lock().unwrap();
-    nodes.push(node);
-    println!("nodes: {:?}", nodes);
+    if alive && store.update(&address, update) {
+        metrics.record(Event::Heartbeat);
+        Ok(HttpResponse::Ok().json(LeaseResponse {
+            message: format!("heartbeat of node {} received", address),
+            ttl: config.node_ttl_secs,
+        }))
+    } else {
+        Err(ApiError::NodeNotFound(address.to_string()))
+    }
+}
 
-    HttpResponse::Ok().body(format!("register node {}:{} successfully", ipv4_address, port))
+/// the query string of `GET /query`
+#[derive(Debug, Deserialize)]
+struct QueryParams {
+    /// the number of nodes wanted, at least 1 (the default), capped by `Config::max_query_count`,
+    /// a legacy query without it gets a single node rather than a list
+    count: Option<usize>,
+    #[serde(default)]
+    family: Family,
+    /// the minimum protocol version of the nodes
+    min_version: Option<u32>,
+    /// the service bits the nodes must all offer
+    #[serde(default)]
+    services: u64,
+    /// the minimum best block height of the nodes
+    min_height: Option<u64>,
+}
+
+impl QueryParams {
+    /// whether `node` has the address family and the metadata asked for
+    fn accepts(&self, node: &Node) -> bool {
+        let Metadata { protocol_version, services, best_height, .. } = &node.metadata;
+        // `None` is below any minimum, and above no minimum
+        node.has_family(self.family)
+            && *protocol_version >= self.min_version
+            && services & self.services == self.services
+            && *best_height >= self.min_height
+    }
 }
 
 #[get("/query")]
-async fn query() -> impl Responder {
+async fn query(
+    params: web::Query<QueryParams>,
+    network: Network,
+    version: ApiVersion,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, ApiError> {
     // query the existing active nodes in the network
-    // randomly poll a node from the list of active nodes
-    // and return its IP address & port & public key
-    let nodes = NODES.lock().unwrap();
+    // randomly poll up to `count` distinct nodes from the list of active nodes
+    // and return their IP addresses & ports
+    let config = &network.config;
+    let store = &network.store;
+
+    // stale and inactive nodes, and nodes that did not pass their recent probes are not served
+    let now = now();
+    if params.count == Some(0) {
+        return Err(ApiError::InvalidQuery("count must be at least 1".to_string()));
+    }
+    let count = params.count.unwrap_or(1).min(config.max_query_count);
+    let sample = |filter: &dyn Fn(&Node) -> bool| match config.query_sampling {
+        SamplingMode::Uniform => store.sample(count, filter),
+        SamplingMode::Diverse => {
+            let candidates = store.list().into_iter().filter(|node| filter(node)).collect();
+            network.diversity.sample(candidates, count)
+        }
+    };
+    let mut response = HttpResponse::Ok();
+    let mut nodes = sample(&|node| params.accepts(node) && node.is_active(now, config));
+    // better a node that was seen recently than none at all
+    if nodes.is_empty() && config.query_fallback_enabled {
+        nodes = sample(&|node| params.accepts(node) && node.is_recently_inactive(now, config));
+        if !nodes.is_empty() {
+            response.insert_header(("X-Node-Pool", "inactive"));
+        }
+    }
 
-    println!("nodes: {:?}", nodes);
+    metrics.record(if nodes.is_empty() { Event::QueryEmpty } else { Event::QueryServed });
 
-    if nodes.is_empty() {
-        return HttpResponse::Ok().json("no active nodes in the network");
+    match version {
+        // the body of the query before `count`: a single node, or a string if there is none
+        ApiVersion::Legacy if params.count.is_none() => Ok(match nodes.into_iter().next() {
+            Some(node) => response.json(node),
+            None => response.json(LEGACY_NO_NODES),
+        }),
+        ApiVersion::Legacy => Ok(response.json(nodes)),
+        ApiVersion::V1 if nodes.is_empty() => Err(ApiError::NoNodes),
+        ApiVersion::V1 => Ok(response.json(NodesResponse { nodes })),
     }
+}
 
-    let node = nodes.choose(&mut rand::thread_rng()).unwrap();
-    let ipv4_address = node.ipv4_address.clone();
-    let port = node.port;
+/// a node of `GET /nodes/inactive`, with its lifecycle state
+#[derive(Debug, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct InactiveNode {
+    #[serde(flatten)]
+    node: Node,
+    state: NodeState,
+}
+
+#[get("/nodes/inactive")]
+async fn inactive_nodes(network: Network, version: ApiVersion) -> impl Responder {
+    let config = &network.config;
+    let store = &network.store;
+    // list the stale and inactive nodes, which are kept for recovery until they are purged
+    let now = now();
+    let nodes: Vec<InactiveNode> = store
+        .list()
+        .into_iter()
+        .map(|node| {
+            let state = node.state(now, config);
+            InactiveNode { node, state }
+        })
+        .filter(|node| node.state != NodeState::Active)
+        .collect();
+    match version {
+        ApiVersion::Legacy => HttpResponse::Ok().json(nodes),
+        ApiVersion::V1 => HttpResponse::Ok().json(NodesResponse { nodes }),
+    }
+}
 
+/// the query string of `GET /v1/events`
+#[derive(Debug, Deserialize)]
+struct EventParams {
+    /// only the events of this network
+    network: Option<String>,
+    /// only the events of the nodes with an address of this family
+    #[serde(default)]
+    family: Family,
+    /// resume the stream after this event, unless the `Last-Event-ID` header is set
+    last_event_id: Option<u64>,
+}
 
-    HttpResponse::Ok().json(Node {
-        ipv4_address,
-        ipv6_address: None,
-        port,
-    })
+#[get("/events")]
+async fn node_events(
+    req: HttpRequest,
+    params: web::Query<EventParams>,
+    networks: web::Data<Networks>,
+    events: web::Data<EventLog>,
+) -> Result<HttpResponse, ApiError> {
+    // stream the node events as Server-Sent Events, see the `events` module
+    let EventParams { network, family, last_event_id } = params.into_inner();
+    if let Some(network) = network.as_ref().filter(|network| networks.get(network).is_none()) {
+        return Err(ApiError::UnknownNetwork(network.clone()));
+    }
+    // a reconnecting `EventSource` sends the ID of the last event it got
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok()?.trim().parse().ok())
+        .or(last_event_id);
+    // the keep-alive is a setting of the process, the same for every network
+    let keepalive = Duration::from_secs(networks.default_network().config.event_keepalive_secs.max(1));
+    let subscription = events.subscribe(last_event_id);
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events::stream(subscription, EventFilter { network, family }, keepalive)))
 }
 
-#[derive(Debug,Deserialize, Serialize)]
-struct Node {
-    /// a node in the network
-    /// and an IP address, either IPv4 or IPv6
-    ipv4_address: String,
-    /// reserved for IPv6
-    ipv6_address: Option<String>,
-    /// and a port
-    port: u16,
+#[get("/openapi.json")]
+async fn openapi_document() -> impl Responder {
+    // the OpenAPI description of the HTTP API
+    HttpResponse::Ok().json(openapi::document())
+}
 
+#[get("/metrics")]
+async fn export_metrics(networks: web::Data<Networks>, metrics: web::Data<Metrics>) -> impl Responder {
+    // the metrics of the DNS server in the Prometheus text format
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render(&networks, now()))
 }
 
-// the list of nodes that are currently active in the network
-// the key is the address of the node
-// the value is the public key of the node
-static NODES: Lazy<Arc<Mutex<Vec<Node>>>> = Lazy::new(||Arc::new(Mutex::new(Vec::new())));
+/// the paths of `node_routes`, without the network prefix
+const NODE_ROUTE_PATHS: &[&str] = &[
+    "/register/challenge",
+    "/register",
+    "/heartbeat",
+    "/deregister",
+    "/query",
+    "/nodes/inactive",
+];
 
+/// whether `path` is one of the node endpoints, with or without a network prefix
+fn is_node_route(path: &str) -> bool {
+    let without_network = path
+        .strip_prefix('/')
+        .and_then(|path| path.find('/').map(|network_len| &path[network_len..]));
+    NODE_ROUTE_PATHS.contains(&path) || without_network.is_some_and(|path| NODE_ROUTE_PATHS.contains(&path))
+}
+
+/// the node endpoints of a network
+fn node_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(register_challenge)
+        .service(register)
+        .service(heartbeat)
+        .service(deregister)
+        .service(query)
+        .service(inactive_nodes);
+}
+
+/// the node endpoints of every network, for the default network at the root
+/// and for every network under `/{network}`
+fn network_routes(cfg: &mut web::ServiceConfig) {
+    // the routes without a prefix first, so that `/{network}` does not shadow them
+    node_routes(cfg);
+    cfg.service(web::scope("/{network}").configure(node_routes));
+}
+
+/// the node endpoints of every version of the API, see the `api` module:
+/// the current one under `/v1`, and the deprecated legacy routes without a version
+fn api_routes(cfg: &mut web::ServiceConfig, legacy_api_sunset: &str) {
+    // `/v1` first, so that the legacy `/{network}` does not shadow it,
+    // and the legacy scope only takes the node endpoints, so that any other path is a plain 404
+    cfg.service(web::scope(api::V1_PREFIX).service(node_events).configure(network_routes)).service(
+        web::scope("")
+            .guard(guard::fn_guard(|ctx| is_node_route(ctx.head().uri.path())))
+            .app_data(ApiVersion::Legacy)
+            .wrap(Deprecated::new(legacy_api_sunset))
+            .configure(network_routes),
+    );
+}
+
+/// open the store selected by the configuration
+fn open_store(config: &Config) -> std::io::Result<Arc<dyn NodeStore>> {
+    if !config.storage_enabled {
+        return Ok(Arc::new(MemoryStore::default()));
+    }
+    let store = FileStore::open(Path::new(&config.storage_dir), now())?;
+    tracing::info!(nodes = store.list().len(), dir = %config.storage_dir, "loaded the stored nodes");
+    Ok(Arc::new(store))
+}
+
+/// periodically deactivate the nodes that stayed stale for the grace period,
+/// and purge the nodes that stayed inactive for the retention period
+fn spawn_reaper(
+    network: String,
+    config: Config,
+    store: Arc<dyn NodeStore>,
+    metrics: Arc<Metrics>,
+    events: Arc<EventLog>,
+) {
+    actix_web::rt::spawn(async move {
+        let mut interval =
+            actix_web::rt::time::interval(Duration::from_secs(config.reap_interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+
+            let now = now();
+            let max_age = config.node_ttl_secs.saturating_add(config.stale_grace_secs);
+            match store.expire(now, max_age) {
+                Ok(expired) => {
+                    for node in expired {
+                        tracing::info!(node = %node.addr(), last_seen = node.last_seen, "node became inactive");
+                        metrics.record(Event::Expired);
+                        events.publish(&network, NodeEventKind::Expired, &node, now);
+                    }
+                }
+                Err(err) => tracing::error!(error = %err, "failed to deactivate the expired nodes"),
+            }
+            match store.purge(now, config.inactive_retention_secs) {
+                Ok(purged) => {
+                    for node in purged {
+                        tracing::debug!(node = %node.addr(), inactive_since = node.inactive_since, "node purged");
+                        metrics.record(Event::Purged);
+                    }
+                }
+                Err(err) => tracing::error!(error = %err, "failed to purge the inactive nodes"),
+            }
+        }
+    }.instrument(tracing::Span::current()));
+}
+
+/// periodically fold the pending changes of the store into a compact form
+fn spawn_compactor(config: Config, store: Arc<dyn NodeStore>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(
+            config.compaction_interval_secs.max(1),
+        ));
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = store.compact() {
+                tracing::error!(error = %err, "failed to compact the node store");
+            }
+        }
+    }.instrument(tracing::Span::current()));
+}
+
+/// periodically probe every registered node with a TCP connect
+fn spawn_prober(
+    network: String,
+    config: Config,
+    store: Arc<dyn NodeStore>,
+    metrics: Arc<Metrics>,
+    events: Arc<EventLog>,
+) {
+    actix_web::rt::spawn(async move {
+        let mut interval =
+            actix_web::rt::time::interval(Duration::from_secs(config.probe_interval_secs.max(1)));
+        let timeout = Duration::from_millis(config.probe_timeout_ms);
+        loop {
+            interval.tick().await;
+
+            // probe a snapshot of the addresses, the store must not be locked while probing,
+            // the inactive nodes are not probed until they register again
+            let targets: Vec<(NodeAddr, SocketAddr)> = store
+                .list()
+                .iter()
+                .filter(|node| node.inactive_since.is_none())
+                .filter_map(|node| Some((node.addr(), node.probe_address()?)))
+                .collect();
+            let mut addresses: Vec<SocketAddr> = targets.iter().map(|(_, address)| *address).collect();
+            addresses.sort();
+            addresses.dedup();
+
+            let results = probe::probe_all(addresses, timeout, config.probe_concurrency).await;
+            let failed = results.iter().filter(|(_, latency)| latency.is_none()).count();
+            tracing::debug!(probed = results.len(), failed, "probe round finished");
+            for (probed, latency) in results {
+                for (address, _) in targets.iter().filter(|(_, target)| *target == probed) {
+                    if latency.is_some() {
+                        metrics.record(Event::ProbeSucceeded);
+                    } else {
+                        tracing::debug!(node = %address, "node failed its health probe");
+                        metrics.record(Event::ProbeFailed);
+                    }
+                    record_probe(&network, &config, store.as_ref(), &events, address, latency);
+                }
+            }
+        }
+    }.instrument(tracing::Span::current()));
+}
+
+/// record the outcome of a probe of the node at `address` of `network`,
+/// and publish the change of its health if the probe changed it
+fn record_probe(
+    network: &str,
+    config: &Config,
+    store: &dyn NodeStore,
+    events: &EventLog,
+    address: &NodeAddr,
+    latency: Option<Duration>,
+) {
+    let now = now();
+    let mut changed = None;
+    store.update(address, &mut |node| {
+        let was_healthy = node.health.is_healthy(config.probe_failure_threshold);
+        node.health.record(latency, now);
+        let healthy = node.health.is_healthy(config.probe_failure_threshold);
+        if healthy != was_healthy {
+            let kind = if healthy { NodeEventKind::Healthy } else { NodeEventKind::Unhealthy };
+            changed = Some((kind, node.clone()));
+        }
+    });
+    if let Some((kind, node)) = changed {
+        events.publish(network, kind, &node, now);
+    }
+}
+
+/// the addresses of the active nodes of `network` listening on `port` at `now`,
+/// the other nodes cannot be served over DNS since a record carries no port
+fn dns_peers(network: &NetworkState, port: u16, now: u64) -> Vec<IpAddr> {
+    network
+        .store
+        .list()
+        .iter()
+        .filter(|node| node.port == port && node.is_active(now, &network.config))
+        .flat_map(|node| node.ip_addresses())
+        .collect()
+}
+
+/// serve the active nodes of `network` over the DNS wire protocol, on both UDP and TCP
+async fn spawn_dns_server(config: &Config, network: Arc<NetworkState>) -> std::io::Result<()> {
+    let port = config.dns_node_port;
+    let peers: dns::PeerSource = Arc::new(move || dns_peers(&network, port, now()));
+    let zone = Arc::new(dns::SeedZone::new(
+        &config.seed_domain,
+        config.dns_record_ttl_secs,
+        config.dns_max_answers,
+        peers,
+    ));
+
+    let socket = actix_web::rt::net::UdpSocket::bind(&config.dns_bind_address).await?;
+    let listener = actix_web::rt::net::TcpListener::bind(&config.dns_bind_address).await?;
+    actix_web::rt::spawn(dns::serve_udp(socket, zone.clone()));
+    actix_web::rt::spawn(dns::serve_tcp(listener, zone));
+
+    tracing::info!(domain = %config.seed_domain, address = %config.dns_bind_address, "serving the DNS seed on udp/tcp");
+    Ok(())
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    const DNS_SERVER_IP: &str = "127.0.0.1";
-    const DNS_SERVER_PORT: u16 = 8080;
-
-    println!("\
-    DNS server for the rusty coin\n\
-    the nodes are able to:\n\
-    this server is only for adding new node in a network\n\
-    - register themselves to the DNS server\n\
-    - query the existing active nodes in the network\n\
-    ");
-
-    println!("DNS server is listening on http://{}:{}", DNS_SERVER_IP, DNS_SERVER_PORT);
-    HttpServer::new(|| {
+    // a variable that is not valid unicode cannot be a setting
+    let vars = std::env::vars_os()
+        .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)));
+    let config = match Config::load(std::env::args().skip(1), vars) {
+        Ok(Command::Run(config)) => config,
+        Ok(Command::CheckConfig(config)) => {
+            // the ASN mapping files are only read on startup, check them as well
+            for name in &config.networks {
+                let diversity = config.network(name).map(|config| Diversity::from_config(&config));
+                if let Ok(Err(err)) = diversity {
+                    eprintln!("invalid configuration: network.{}.asn_map_file: {}", name, err);
+                    std::process::exit(2);
+                }
+            }
+            match config.to_toml() {
+                Ok(printed) => println!("{}", printed),
+                Err(err) => {
+                    eprintln!("invalid configuration: {}", err);
+                    std::process::exit(2);
+                }
+            }
+            return Ok(());
+        }
+        Ok(Command::Help) => {
+            println!("{}", config::USAGE);
+            return Ok(());
+        }
+        Err(err) => {
+            eprintln!("invalid configuration: {}", err);
+            std::process::exit(2);
+        }
+    };
+
+    logging::init(&config);
+    tracing::info!("DNS server for the rusty coin");
+
+    let metrics = Arc::new(Metrics::default());
+    let events = Arc::new(EventLog::new(config.event_log_capacity));
+    let mut networks = Vec::new();
+    for name in &config.networks {
+        // the logs of a network, and of its background tasks, are in its span
+        let _span = tracing::info_span!("network", network = %name).entered();
+        let network_config = config
+            .network(name)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        let diversity = Diversity::from_config(&network_config)?;
+        let store = open_store(&network_config)?;
+        spawn_compactor(network_config.clone(), store.clone());
+
+        tracing::info!(
+            node_ttl_secs = network_config.node_ttl_secs,
+            stale_grace_secs = network_config.stale_grace_secs,
+            inactive_retention_secs = network_config.inactive_retention_secs,
+            "nodes expire without a heartbeat"
+        );
+        spawn_reaper(name.clone(), network_config.clone(), store.clone(), metrics.clone(), events.clone());
+        if network_config.probe_enabled {
+            spawn_prober(name.clone(), network_config.clone(), store.clone(), metrics.clone(), events.clone());
+        }
+        networks.push(NetworkState::new(name, network_config, store, diversity));
+    }
+    let networks = web::Data::new(Networks::new(networks));
+    let bans = if config.storage_enabled {
+        let bans = BanList::open(&Path::new(&config.storage_dir).join(admin::BANS_FILE))?;
+        tracing::info!(bans = bans.list(now()).len(), "loaded the bans");
+        bans
+    } else {
+        BanList::default()
+    };
+    let bans = web::Data::new(bans);
+    if config.dns_enabled {
+        spawn_dns_server(&config, networks.default_network().clone()).await?;
+    }
+
+    tracing::info!(address = %config.http_bind_address, "HTTP API is listening");
+    let bind_address = config.http_bind_address.clone();
+    let workers = config.http_workers;
+    let replay_guard = web::Data::new(ReplayGuard::new(config.signature_max_skew_secs));
+    // the buckets are shared by all the workers
+    let rate_limiter = Arc::new(RateLimiter::new(&config));
+    let trusted_proxies = config.trusted_proxies.clone();
+    let metrics_data = web::Data::from(metrics.clone());
+    let events = web::Data::from(events);
+    let legacy_api_sunset = config.legacy_api_sunset.clone();
+    let admin_enabled = !config.admin_tokens.is_empty();
+    if admin_enabled {
+        tracing::info!(operators = config.admin_tokens.len(), "serving the admin API");
+    }
+    let admin = web::Data::new(Admin::new(&config));
+    let mut server = HttpServer::new(move || {
         App::new()
+            .wrap(RateLimit::new(rate_limiter.clone(), trusted_proxies.clone()))
+            .wrap(RecordMetrics::new(metrics.clone()))
+            .wrap(RequestTrace)
+            .app_data(networks.clone())
+            .app_data(replay_guard.clone())
+            .app_data(metrics_data.clone())
+            .app_data(events.clone())
+            .app_data(bans.clone())
+            .app_data(admin.clone())
+            .app_data(web::JsonConfig::default().error_handler(validation::json_error_handler))
+            .app_data(web::QueryConfig::default().error_handler(validation::query_error_handler))
             .service(index)
-            .service(register)
-            .service(deregister)
-            .service(query)
-    })
-        .bind((DNS_SERVER_IP,DNS_SERVER_PORT))?
-        .run()
-        .await
+            .service(export_metrics)
+            .service(openapi_document)
+            .configure(|cfg| {
+                if admin_enabled {
+                    admin::routes(cfg);
+                }
+            })
+            .configure(|cfg| api_routes(cfg, &legacy_api_sunset))
+            .default_service(web::to(not_found))
+    });
+    if workers > 0 {
+        server = server.workers(workers);
+    }
+    server.bind(bind_address)?.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use crate::node::test_node;
+    use crate::validation::MismatchPolicy;
+
+    /// the default configuration, never probing
+    fn test_config() -> Config {
+        Config {
+            probe_enabled: false,
+            ..Config::default()
+        }
+    }
+
+    /// the app data of the node handlers with `config`, a single network on top of an in-memory store
+    fn app_data(config: Config) -> (impl FnOnce(&mut web::ServiceConfig), Arc<dyn NodeStore>) {
+        let store: Arc<dyn NodeStore> = Arc::new(MemoryStore::default());
+        let diversity = Diversity::from_config(&config).unwrap();
+        let replay_guard = ReplayGuard::new(config.signature_max_skew_secs);
+        let network = NetworkState::new("mainnet", config, store.clone(), diversity);
+        let configure = move |cfg: &mut web::ServiceConfig| {
+            cfg.app_data(web::Data::new(replay_guard))
+                .app_data(web::Data::new(Networks::new(vec![network])))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(EventLog::new(16)))
+                .app_data(web::Data::new(BanList::default()))
+                .app_data(web::JsonConfig::default().error_handler(validation::json_error_handler))
+                .app_data(web::QueryConfig::default().error_handler(validation::query_error_handler));
+        };
+        (configure, store)
+    }
+
+    #[actix_web::test]
+    async fn registered_node_is_queried_until_deregistered() {
+        let (app_data, store) = app_data(Config {
+            query_fallback_enabled: false,
+            ..test_config()
+        });
+        let app = test::init_service(
+            App::new()
+                .configure(app_data)
+                .service(register)
+                .service(deregister)
+                .service(query),
+        )
+        .await;
+        let body = serde_json::json!({"ipv4_address": "93.184.216.34", "port": 8333});
+
+        let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::CREATED);
+        let lease: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(lease["ttl"], Config::default().node_ttl_secs);
+
+        // registering again refreshes the node instead of adding a duplicate
+        let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(store.list().len(), 1);
+
+        let request = test::TestRequest::get().uri("/query").to_request();
+        let nodes: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(nodes["nodes"][0]["ipv4_address"], "93.184.216.34");
+        assert_eq!(nodes["nodes"][0]["port"], 8333);
+
+        let request = test::TestRequest::post().uri("/deregister").set_json(&body).to_request();
+        assert!(test::call_service(&app, request).await.status().is_success());
+        assert!(store.list()[0].inactive_since.is_some());
+
+        let request = test::TestRequest::get().uri("/query").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let error: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(error["error"], "no_nodes");
+    }
+
+    #[actix_web::test]
+    async fn query_returns_distinct_nodes_up_to_count() {
+        let (app_data, store) = app_data(test_config());
+        let max_query_count = test_config().max_query_count;
+        for port in 1..=(max_query_count as u16 + 5) {
+            let node = Node {
+                ipv4_address: Some("93.184.216.34".parse().unwrap()),
+                ipv6_address: None,
+                port,
+                public_key: None,
+                last_seen: now(),
+                health: Health::default(),
+                metadata: Metadata::default(),
+                inactive_since: None,
+            };
+            store.insert_if(node, &mut |_| true).unwrap();
+        }
+        let app = test::init_service(App::new().configure(app_data).service(query)).await;
+
+        let request = test::TestRequest::get().uri("/query?count=0").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(error["error"], "invalid_query");
+
+        for (count, expected) in [(1, 1), (5, 5), (1000, max_query_count)] {
+            let uri = format!("/query?count={}", count);
+            let request = test::TestRequest::get().uri(&uri).to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+            let mut ports: Vec<u64> = body["nodes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|node| node["port"].as_u64().unwrap())
+                .collect();
+            ports.sort();
+            ports.dedup();
+            assert_eq!(ports.len(), expected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn inactive_nodes_are_kept_for_recovery_until_purged() {
+        let config = test_config();
+        let (app_data, store) = app_data(config.clone());
+        let app = test::init_service(
+            App::new()
+                .configure(app_data)
+                .service(register)
+                .service(heartbeat)
+                .service(deregister)
+                .service(query)
+                .service(inactive_nodes),
+        )
+        .await;
+        let stale = serde_json::json!({"ipv4_address": "93.184.216.34", "port": 8333});
+        let deregistered = serde_json::json!({"ipv4_address": "198.41.0.4", "port": 8333});
+        for body in [&stale, &deregistered] {
+            let request = test::TestRequest::post().uri("/register").set_json(body).to_request();
+            assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED);
+        }
+        let request = test::TestRequest::post().uri("/deregister").set_json(&deregistered).to_request();
+        assert!(test::call_service(&app, request).await.status().is_success());
+        // the TTL of the other node lapses
+        let stale_addr = NodeAddr {
+            ipv4_address: Some("93.184.216.34".parse().unwrap()),
+            ipv6_address: None,
+            port: 8333,
+        };
+        store.update(&stale_addr, &mut |node| node.last_seen = now() - config.node_ttl_secs);
+
+        let request = test::TestRequest::get().uri("/nodes/inactive").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        let mut states: Vec<(String, String)> = body["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|node| (node["ipv4_address"].to_string(), node["state"].to_string()))
+            .collect();
+        states.sort();
+        assert_eq!(
+            states,
+            [
+                (r#""198.41.0.4""#.to_string(), r#""inactive""#.to_string()),
+                (r#""93.184.216.34""#.to_string(), r#""stale""#.to_string()),
+            ]
+        );
+
+        // with no active node, the recently seen ones are handed out
+        let request = test::TestRequest::get().uri("/query?count=2").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.headers().get("X-Node-Pool").unwrap(), "inactive");
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["nodes"].as_array().unwrap().len(), 2);
+
+        // a heartbeat revives the stale node, but not the inactive one
+        let request = test::TestRequest::post().uri("/heartbeat").set_json(&stale).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        let request = test::TestRequest::post().uri("/heartbeat").set_json(&deregistered).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+        let request = test::TestRequest::get().uri("/query?count=2").to_request();
+        let response = test::call_service(&app, request).await;
+        assert!(response.headers().get("X-Node-Pool").is_none());
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["nodes"].as_array().unwrap().len(), 1);
+        assert_eq!(body["nodes"][0]["ipv4_address"], "93.184.216.34");
+
+        // registering again makes the inactive node a new node
+        let request = test::TestRequest::post().uri("/register").set_json(&deregistered).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED);
+        assert!(store.list().iter().all(|node| node.inactive_since.is_none()));
+
+        // the nodes are purged after the retention period
+        store.deactivate(&stale_addr, now() - config.inactive_retention_secs).unwrap();
+        let purged = store.purge(now(), config.inactive_retention_secs).unwrap();
+        assert_eq!(purged.len(), 1);
+        assert_eq!(store.list().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn queries_filter_on_the_node_metadata() {
+        let (app_data, store) = app_data(test_config());
+        let app = test::init_service(
+            App::new()
+                .configure(app_data)
+                .service(register)
+                .service(heartbeat)
+                .service(query),
+        )
+        .await;
+        for body in [
+            serde_json::json!({"ipv4_address": "93.184.216.34", "port": 8333, "protocol_version": 70016,
+                "services": 0b1001, "user_agent": "/rusty_coin:0.1.0/", "best_height": 1000}),
+            serde_json::json!({"ipv4_address": "198.41.0.4", "port": 8333, "protocol_version": 70015,
+                "services": 0b0001, "best_height": 900}),
+            serde_json::json!({"ipv6_address": "2606:2800:220:1::1", "port": 8333}),
+        ] {
+            let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+            assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED);
+        }
+        let request = test::TestRequest::get().uri("/query").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert!(body["nodes"][0].get("user_agent").is_some());
+
+        for (filter, expected) in [
+            ("", vec!["198.41.0.4", "2606:2800:220:1::1", "93.184.216.34"]),
+            ("&min_version=70016", vec!["93.184.216.34"]),
+            ("&services=1", vec!["198.41.0.4", "93.184.216.34"]),
+            ("&services=8&min_version=70015", vec!["93.184.216.34"]),
+            ("&min_height=950", vec!["93.184.216.34"]),
+            ("&min_height=2000", vec![]),
+        ] {
+            let uri = format!("/query?count=10{}", filter);
+            let request = test::TestRequest::get().uri(&uri).to_request();
+            // no `nodes` when nothing matches
+            let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+            let nodes = body["nodes"].as_array().cloned().unwrap_or_default();
+            let mut addresses: Vec<&str> = nodes
+                .iter()
+                .map(|node| node["ipv4_address"].as_str().or(node["ipv6_address"].as_str()).unwrap())
+                .collect();
+            addresses.sort();
+            assert_eq!(addresses, expected, "{}", filter);
+        }
+
+        // a heartbeat moves the best height forward
+        let body = serde_json::json!({"ipv4_address": "198.41.0.4", "port": 8333, "best_height": 2000});
+        let request = test::TestRequest::post().uri("/heartbeat").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        let request = test::TestRequest::get().uri("/query?min_height=2000").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body["nodes"][0]["ipv4_address"], "198.41.0.4");
+        assert_eq!(body["nodes"][0]["protocol_version"], 70015);
+
+        let body = serde_json::json!({"ipv4_address": "93.184.216.34", "port": 8334, "user_agent": "bad\tagent"});
+        let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(error["field"], "user_agent");
+        assert_eq!(store.list().len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn networks_keep_their_nodes_apart() {
+        let config = Config {
+            networks: vec!["mainnet".to_string(), "testnet".to_string()],
+            ..test_config()
+        };
+        let mut networks = Vec::new();
+        let mut stores = Vec::new();
+        for name in &config.networks {
+            let store: Arc<dyn NodeStore> = Arc::new(MemoryStore::default());
+            let network_config = config.network(name).unwrap();
+            let diversity = Diversity::from_config(&network_config).unwrap();
+            networks.push(NetworkState::new(name, network_config, store.clone(), diversity));
+            stores.push(store);
+        }
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(ReplayGuard::new(config.signature_max_skew_secs)))
+                .app_data(web::Data::new(Networks::new(networks)))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(EventLog::new(16)))
+                .app_data(web::Data::new(BanList::default()))
+                .configure(network_routes),
+        )
+        .await;
+
+        let body = serde_json::json!({"ipv4_address": "93.184.216.34", "port": 18333});
+        let request = test::TestRequest::post().uri("/testnet/register").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED);
+        assert!(stores[0].list().is_empty());
+        assert_eq!(stores[1].list().len(), 1);
+
+        // the routes without a prefix serve the default network
+        for (uri, status) in [
+            ("/testnet/query", StatusCode::OK),
+            ("/mainnet/query", StatusCode::NOT_FOUND),
+            ("/query", StatusCode::NOT_FOUND),
+        ] {
+            let request = test::TestRequest::get().uri(uri).to_request();
+            assert_eq!(test::call_service(&app, request).await.status(), status, "{}", uri);
+        }
+
+        let request = test::TestRequest::get().uri("/signet/query").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let error: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(error["error"], "unknown_network");
+    }
+
+    #[actix_web::test]
+    async fn errors_carry_a_code() {
+        let (app_data, _) = app_data(test_config());
+        let app = test::init_service(
+            App::new()
+                .configure(app_data)
+                .service(heartbeat)
+                .default_service(web::to(not_found)),
+        )
+        .await;
+
+        let body = serde_json::json!({"ipv4_address": "93.184.216.34", "port": 8333});
+        for (request, status, code) in [
+            (test::TestRequest::get().uri("/nowhere"), StatusCode::NOT_FOUND, "not_found"),
+            (test::TestRequest::post().uri("/heartbeat").set_json(&body), StatusCode::NOT_FOUND, "node_not_found"),
+        ] {
+            let response = test::call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), status);
+            let error: serde_json::Value = test::read_body_json(response).await;
+            assert_eq!(error["error"], code);
+            assert!(error["message"].is_string());
+        }
+    }
+
+    #[actix_web::test]
+    async fn legacy_routes_keep_their_bodies_and_point_to_v1() {
+        let (app_data, _) = app_data(Config {
+            query_fallback_enabled: false,
+            ..test_config()
+        });
+        let app = test::init_service(
+            App::new()
+                .configure(app_data)
+                .configure(|cfg| api_routes(cfg, "Sun, 01 Mar 2026 00:00:00 GMT"))
+                .default_service(web::to(not_found)),
+        )
+        .await;
+        // the bodies of the first release: text for a registration, a single node or a string
+        let request = test::TestRequest::get().uri("/query").to_request();
+        let text = test::call_and_read_body(&app, request).await;
+        assert_eq!(text, r#""no active nodes in the network""#);
+        let body = serde_json::json!({"ipv4_address": "93.184.216.34", "port": 8333});
+        for _ in 0..2 {
+            let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let text = test::read_body(response).await;
+            assert_eq!(text, "register node 93.184.216.34:8333 successfully");
+        }
+        let request = test::TestRequest::get().uri("/query").to_request();
+        let node: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(node["ipv4_address"], "93.184.216.34");
+        assert_eq!((&node["ipv6_address"], &node["port"]), (&serde_json::Value::Null, &body["port"]));
+
+        let request = test::TestRequest::post().uri("/v1/register").set_json(&body).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("Deprecation").is_none());
+        let lease: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(lease["message"], "refresh node 93.184.216.34:8333 successfully");
+
+        // both versions serve the same nodes, in their own bodies
+        for uri in ["/v1/query", "/v1/mainnet/query"] {
+            let request = test::TestRequest::get().uri(uri).to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+            assert_eq!(body["nodes"][0]["ipv4_address"], "93.184.216.34", "{}", uri);
+        }
+        let request = test::TestRequest::get().uri("/mainnet/query?count=2").to_request();
+        let response = test::call_service(&app, request).await;
+        let headers = response.headers();
+        assert_eq!(headers.get("Deprecation").unwrap(), "true");
+        assert_eq!(headers.get("Link").unwrap(), r#"</v1/mainnet/query>; rel="successor-version""#);
+        assert_eq!(headers.get("Sunset").unwrap(), "Sun, 01 Mar 2026 00:00:00 GMT");
+        let nodes: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(nodes[0]["ipv4_address"], "93.184.216.34");
+        for uri in ["/register/challenge", "/nodes/inactive", "/mainnet/nodes/inactive"] {
+            let request = test::TestRequest::get().uri(uri).to_request();
+            assert!(test::call_service(&app, request).await.headers().contains_key("Deprecation"), "{}", uri);
+        }
+
+        // a path that never was a route is not deprecated, it is not found
+        for uri in ["/nope", "/mainnet/nope", "/mainnet/query/nope"] {
+            let request = test::TestRequest::get().uri(uri).to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+            assert!(!response.headers().contains_key("Deprecation"), "{}", uri);
+            let error: serde_json::Value = test::read_body_json(response).await;
+            assert_eq!(error["error"], "not_found");
+        }
+
+        let request = test::TestRequest::post().uri("/deregister").set_json(&body).to_request();
+        let text = test::call_and_read_body(&app, request).await;
+        assert_eq!(text, "deregister node 93.184.216.34:8333 successfully");
+        let request = test::TestRequest::get().uri("/query?count=2").to_request();
+        let nodes: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(nodes, serde_json::json!([]));
+        let request = test::TestRequest::get().uri("/v1/query").to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+        let request = test::TestRequest::get().uri("/v1/signet/query").to_request();
+        let error: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(error["error"], "unknown_network");
+    }
+
+    /// the names of the fields of the struct `T`, as serde deserializes them
+    fn field_names<'de, T: Deserialize<'de>>() -> Vec<&'static str> {
+        struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+        impl<'de> serde::Deserializer<'de> for FieldNames<'_> {
+            type Error = serde::de::value::Error;
+
+            fn deserialize_any<V: serde::de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+                Err(serde::de::Error::custom("not a struct"))
+            }
+
+            fn deserialize_struct<V: serde::de::Visitor<'de>>(
+                self,
+                _name: &'static str,
+                fields: &'static [&'static str],
+                _visitor: V,
+            ) -> Result<V::Value, Self::Error> {
+                *self.0 = fields;
+                Err(serde::de::Error::custom("only the fields are wanted"))
+            }
+
+            serde::forward_to_deserialize_any! {
+                bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+                option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+            }
+        }
+
+        let mut fields: &'static [&'static str] = &[];
+        let _ = T::deserialize(FieldNames(&mut fields));
+        let mut fields = fields.to_vec();
+        fields.sort();
+        fields
+    }
+
+    #[actix_web::test]
+    async fn openapi_document_matches_the_handlers() {
+        let (app_data, _) = app_data(test_config());
+        let app = test::init_service(
+            App::new()
+                .configure(app_data)
+                .service(index)
+                .service(export_metrics)
+                .service(openapi_document)
+                .configure(|cfg| api_routes(cfg, ""))
+                .default_service(web::to(not_found)),
+        )
+        .await;
+        let request = test::TestRequest::get().uri("/openapi.json").to_request();
+        let document: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+
+        // the request types have the documented fields
+        let schemas = &document["components"]["schemas"];
+        let mut properties: Vec<&str> =
+            schemas["NodeRequest"]["properties"].as_object().unwrap().keys().map(String::as_str).collect();
+        properties.sort();
+        assert_eq!(properties, field_names::<NodeRequest>());
+        let mut parameters: Vec<&str> = document["paths"]["/v1/query"]["get"]["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|parameter| parameter["name"].as_str().unwrap())
+            .collect();
+        parameters.sort();
+        assert_eq!(parameters, field_names::<QueryParams>());
+        let mut parameters: Vec<&str> = document["paths"]["/v1/events"]["get"]["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|parameter| parameter["name"].as_str().unwrap())
+            .collect();
+        parameters.sort();
+        assert_eq!(parameters, field_names::<EventParams>());
+
+        // every operation is served, and answers a documented status with a documented body,
+        // both while the node is registered and once it is deregistered
+        let example = &schemas["NodeRequest"]["example"];
+        let mut answered = Vec::new();
+        for setup in ["/v1/register", "/v1/deregister"] {
+            for (path, item) in document["paths"].as_object().unwrap() {
+                for (method, operation) in item.as_object().unwrap() {
+                    for uri in ["/v1/register", setup] {
+                        let request = test::TestRequest::post().uri(uri).set_json(example).to_request();
+                        assert!(test::call_service(&app, request).await.status().is_success(), "{}", uri);
+                    }
+                    let uri = path.replace("{network}", "mainnet");
+                    let mut request = test::TestRequest::default()
+                        .method(method.to_uppercase().parse().unwrap())
+                        .uri(&uri);
+                    if operation.get("requestBody").is_some() {
+                        request = request.set_json(example);
+                    }
+                    let response = test::call_service(&app, request.to_request()).await;
+                    let status = response.status().as_u16();
+                    let documented = &operation["responses"][status.to_string()];
+                    assert!(documented.is_object(), "{} {} answered {}", method, path, status);
+                    if let Some(schema) = documented["content"]["application/json"].get("schema") {
+                        let body: serde_json::Value = test::read_body_json(response).await;
+                        if let Err(err) = openapi::validate(&document, schema, &body) {
+                            panic!("{} {} answered {} with {}: {}", method, path, status, body, err);
+                        }
+                    }
+                    answered.push(format!("{} {} {}", method, path, status));
+                }
+            }
+        }
+        for answer in [
+            "post /v1/register 200",
+            "post /v1/register 201",
+            "post /v1/deregister 200",
+            "get /v1/nodes/inactive 200",
+            "get /v1/query 200",
+        ] {
+            assert!(answered.iter().any(|answered| answered == answer), "{} in {:?}", answer, answered);
+        }
+    }
+
+    #[actix_web::test]
+    async fn node_events_are_streamed_and_resumed() {
+        use actix_web::body::MessageBody;
+
+        let (app_data, _) = app_data(test_config());
+        let app = test::init_service(
+            App::new()
+                .configure(app_data)
+                .configure(|cfg| api_routes(cfg, ""))
+                .default_service(web::to(not_found)),
+        )
+        .await;
+        let body = serde_json::json!({"ipv4_address": "93.184.216.34", "port": 8333});
+        let request = test::TestRequest::post().uri("/v1/register").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED);
+
+        // a subscriber resuming from the start gets the registration
+        let request = test::TestRequest::get().uri("/v1/events?last_event_id=0&family=v4").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.headers().get("Content-Type").unwrap(), "text/event-stream");
+        let mut stream = std::pin::pin!(response.into_body());
+        let chunk = futures_util::future::poll_fn(|cx| stream.as_mut().poll_next(cx)).await;
+        let chunk = String::from_utf8(chunk.unwrap().ok().unwrap().to_vec()).unwrap();
+        assert!(chunk.starts_with("id: 1\nevent: registered\ndata: {"), "{}", chunk);
+        assert!(chunk.contains(r#""network":"mainnet""#) && chunk.contains(r#""port":8333"#), "{}", chunk);
+
+        // a new subscriber only gets the new events, filtered
+        let mut streams = Vec::new();
+        for uri in ["/v1/events?family=v6", "/v1/events?network=mainnet"] {
+            let request = test::TestRequest::get().uri(uri).to_request();
+            streams.push(test::call_service(&app, request).await.into_body());
+        }
+        let request = test::TestRequest::post().uri("/v1/deregister").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        let mut stream = std::pin::pin!(streams.pop().unwrap());
+        let chunk = futures_util::future::poll_fn(|cx| stream.as_mut().poll_next(cx)).await;
+        let chunk = String::from_utf8(chunk.unwrap().ok().unwrap().to_vec()).unwrap();
+        assert!(chunk.starts_with("id: 2\nevent: deregistered\n"), "{}", chunk);
+        let mut stream = std::pin::pin!(streams.pop().unwrap());
+        let pending = futures_util::future::poll_fn(|cx| std::task::Poll::Ready(stream.as_mut().poll_next(cx))).await;
+        assert!(pending.is_pending());
+
+        let request = test::TestRequest::get().uri("/v1/events?network=signet").to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn operators_list_remove_and_ban_nodes() {
+        let config = Config {
+            admin_tokens: vec!["alice:0123456789abcdef".parse().unwrap()],
+            ..test_config()
+        };
+        let admin = web::Data::new(Admin::new(&config));
+        let (app_data, store) = app_data(config);
+        let app = test::init_service(
+            App::new()
+                .configure(app_data)
+                .app_data(admin)
+                .configure(admin::routes)
+                .configure(|cfg| api_routes(cfg, ""))
+                .default_service(web::to(not_found)),
+        )
+        .await;
+        for (ip, port) in [("93.184.216.34", 8333), ("93.184.216.35", 8333), ("198.41.0.4", 8333)] {
+            let body = serde_json::json!({"ipv4_address": ip, "port": port});
+            let request = test::TestRequest::post().uri("/v1/register").set_json(&body).to_request();
+            assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED);
+        }
+        let admin_request = |request: test::TestRequest| {
+            request.insert_header(("Authorization", "Bearer 0123456789abcdef")).to_request()
+        };
+
+        for token in [None, Some("Bearer 0123456789abcdeg"), Some("0123456789abcdef")] {
+            let mut request = test::TestRequest::get().uri("/admin/nodes");
+            if let Some(token) = token {
+                request = request.insert_header(("Authorization", token));
+            }
+            let response = test::call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            let error: serde_json::Value = test::read_body_json(response).await;
+            assert_eq!(error["error"], "unauthorized");
+        }
+
+        // the nodes are listed in pages, in the order of their addresses
+        let request = admin_request(test::TestRequest::get().uri("/admin/nodes?per_page=2&page=2"));
+        let page: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!((&page["page"], &page["per_page"], &page["total"]), (&2.into(), &2.into(), &3.into()));
+        assert_eq!(page["items"][0]["ipv4_address"], "198.41.0.4");
+        assert_eq!(page["items"][0]["state"], "active");
+        let request = admin_request(test::TestRequest::get().uri("/admin/mainnet/nodes?address=93.184.216.0/24"));
+        let page: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(page["total"], 2);
+        let request = admin_request(test::TestRequest::get().uri("/admin/nodes?address=93.184.216.0/33"));
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::BAD_REQUEST);
+
+        // an edit only changes the fields it sets, and can take a node out of the queries and back
+        let edit = |body: serde_json::Value| {
+            admin_request(test::TestRequest::patch().uri("/admin/nodes/198.41.0.4/8333").set_json(body))
+        };
+        let request = edit(serde_json::json!({"user_agent": "/patched:1.0/", "best_height": 42}));
+        let edited: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!((&edited["user_agent"], &edited["best_height"]), (&"/patched:1.0/".into(), &42.into()));
+        assert_eq!(edited["state"], "active");
+        let address = NodeAddr { ipv4_address: Some([198, 41, 0, 4].into()), ipv6_address: None, port: 8333 };
+        for state in ["inactive", "active"] {
+            let request = edit(serde_json::json!({"state": state}));
+            let edited: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+            assert_eq!((&edited["state"], &edited["user_agent"]), (&state.into(), &"/patched:1.0/".into()));
+            assert_eq!(store.find(&address)[0].inactive_since.is_none(), state == "active");
+        }
+        for body in [serde_json::json!({}), serde_json::json!({"state": "stale"}), serde_json::json!({"port": 1})] {
+            let response = test::call_service(&app, edit(body.clone())).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+        let body = serde_json::json!({"best_height": 1});
+        let request = admin_request(test::TestRequest::patch().uri("/admin/nodes/198.41.0.5/8333").set_json(body));
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+
+        let request = admin_request(test::TestRequest::delete().uri("/admin/nodes/198.41.0.4/8333"));
+        let removed: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(removed["nodes"][0]["ipv4_address"], "198.41.0.4");
+        assert_eq!(store.list().len(), 2);
+        let request = admin_request(test::TestRequest::delete().uri("/admin/nodes/198.41.0.4/8333"));
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+
+        // a ban removes the nodes in the range, which cannot come back until it is lifted
+        let ban = serde_json::json!({"address": "93.184.216.0/24", "reason": "sybil", "expires_in": 3600});
+        let request = admin_request(test::TestRequest::post().uri("/admin/bans").set_json(&ban));
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let created: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(created["removed"], 2);
+        assert_eq!(created["ban"]["address"], "93.184.216.0/24");
+        assert!(store.list().is_empty());
+        let body = serde_json::json!({"ipv4_address": "93.184.216.34", "port": 8333});
+        let request = test::TestRequest::post().uri("/v1/register").set_json(&body).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let error: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(error["error"], "banned");
+        assert!(error["message"].as_str().unwrap().contains("sybil"));
+        let unbounded = serde_json::json!({"reason": "no target"});
+        let request = admin_request(test::TestRequest::post().uri("/admin/bans").set_json(&unbounded));
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::BAD_REQUEST);
+
+        let request = admin_request(test::TestRequest::get().uri("/admin/bans"));
+        let bans: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        let id = bans["bans"][0]["id"].as_u64().unwrap();
+        let request = admin_request(test::TestRequest::delete().uri(&format!("/admin/bans/{}", id)));
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        let request = admin_request(test::TestRequest::delete().uri(&format!("/admin/bans/{}", id)));
+        let error: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(error["error"], "ban_not_found");
+        let request = test::TestRequest::post().uri("/v1/register").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED);
+
+        // every change is audited, newest first
+        let request = admin_request(test::TestRequest::get().uri("/admin/audit"));
+        let audit: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        let actions: Vec<&str> = audit["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["action"].as_str().unwrap())
+            .collect();
+        assert_eq!(actions, ["unban", "ban", "remove_node", "edit_node", "edit_node", "edit_node"]);
+        assert_eq!(audit["items"][2]["operator"], "alice");
+        assert_eq!(audit["items"][2]["target"], "198.41.0.4:8333");
+        assert_eq!(audit["items"][3]["detail"], r#"edited in mainnet: {"state":"active"}"#);
+    }
+
+    #[actix_web::test]
+    async fn nodes_failing_their_probes_are_not_queried() {
+        let config = Config {
+            probe_enabled: true,
+            probe_failure_threshold: 2,
+            probe_timeout_ms: 1000,
+            allow_private_addresses: true,
+            query_fallback_enabled: false,
+            ..Config::default()
+        };
+        let (app_data, store) = app_data(config.clone());
+        let events = EventLog::new(16);
+        let app = test::init_service(App::new().configure(app_data).service(register).service(query)).await;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let body = serde_json::json!({"ipv4_address": "127.0.0.1", "port": port});
+        let address = NodeAddr {
+            ipv4_address: Some("127.0.0.1".parse().unwrap()),
+            ipv6_address: None,
+            port,
+        };
+        let probe_node = || async {
+            let target = store.find(&address)[0].probe_address().unwrap();
+            let latency = probe::probe(target, Duration::from_secs(1)).await;
+            record_probe("mainnet", &config, store.as_ref(), &events, &address, latency);
+        };
+
+        // a new node is probed right away, and handed out once it passed the probe
+        let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED);
+        while store.find(&address)[0].health.last_probe.is_none() {
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        let request = test::TestRequest::get().uri("/query").to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+
+        // the port closes, the node is taken out after the threshold of failed probes
+        drop(listener);
+        probe_node().await;
+        let request = test::TestRequest::get().uri("/query").to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        probe_node().await;
+        assert_eq!(store.list()[0].health.consecutive_failures, 2);
+        let request = test::TestRequest::get().uri("/query").to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn invalid_address_is_rejected_with_the_failing_field() {
+        let (app_data, store) = app_data(test_config());
+        let app = test::init_service(
+            App::new()
+                .configure(app_data)
+                .service(register),
+        )
+        .await;
+
+        for (body, field) in [
+            (serde_json::json!({"ipv4_address": "999.1.1.1", "port": 8333}), "ipv4_address"),
+            (serde_json::json!({"ipv4_address": "10.0.0.1", "port": 8333}), "ipv4_address"),
+            (serde_json::json!({"ipv4_address": "100.64.0.1", "port": 8333}), "ipv4_address"),
+            (serde_json::json!({"ipv4_address": "100.127.255.254", "port": 8333}), "ipv4_address"),
+            (serde_json::json!({"ipv4_address": "198.19.0.1", "port": 8333}), "ipv4_address"),
+            (serde_json::json!({"ipv4_address": "240.0.0.1", "port": 8333}), "ipv4_address"),
+            (serde_json::json!({"ipv4_address": "192.0.0.8", "port": 8333}), "ipv4_address"),
+            (serde_json::json!({"ipv6_address": "::ffff:100.64.0.1", "port": 8333}), "ipv6_address"),
+            (serde_json::json!({"ipv4_address": "93.184.216.34", "port": 0}), "port"),
+            (serde_json::json!({"ipv6_address": "2001:db8::1", "port": 8333}), "ipv6_address"),
+            (serde_json::json!({"port": 8333}), "ipv4_address"),
+        ] {
+            let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+            let error: serde_json::Value = test::read_body_json(response).await;
+            assert_eq!(error["error"], "invalid_field");
+            assert_eq!(error["field"], field);
+        }
+
+        let body = serde_json::json!({"ipv4_address": "93.184.216.34", "port": "hello"});
+        let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+        let error: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(error["error"], "invalid_body");
+        assert!(store.list().is_empty());
+
+        // the addresses right next to the reserved ranges are public
+        for ipv4_address in ["100.128.0.1", "198.20.0.1", "192.0.1.1"] {
+            let body = serde_json::json!({"ipv4_address": ipv4_address, "port": 8333});
+            let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+            assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED, "{}", ipv4_address);
+        }
+    }
+
+    #[actix_web::test]
+    async fn address_mismatch_policy_uses_the_observed_address() {
+        let peer: SocketAddr = "93.184.216.34:50000".parse().unwrap();
+        let other = serde_json::json!({"ipv4_address": "198.41.0.4", "port": 8333});
+        let port_only = serde_json::json!({"port": 8333});
+
+        for (policy, body, status, registered) in [
+            (MismatchPolicy::Allow, &other, StatusCode::CREATED, "198.41.0.4"),
+            (MismatchPolicy::Allow, &port_only, StatusCode::CREATED, "93.184.216.34"),
+            (MismatchPolicy::Override, &other, StatusCode::CREATED, "93.184.216.34"),
+            (MismatchPolicy::Reject, &other, StatusCode::BAD_REQUEST, ""),
+            (MismatchPolicy::Reject, &port_only, StatusCode::CREATED, "93.184.216.34"),
+        ] {
+            let (app_data, store) = app_data(Config {
+                address_mismatch_policy: policy,
+                ..test_config()
+            });
+            let app = test::init_service(App::new().configure(app_data).service(register)).await;
+
+            let request = test::TestRequest::post()
+                .uri("/register")
+                .peer_addr(peer)
+                .set_json(body)
+                .to_request();
+            assert_eq!(test::call_service(&app, request).await.status(), status);
+            let nodes: Vec<String> = store
+                .list()
+                .iter()
+                .filter_map(|node| node.ipv4_address.map(|ip| ip.to_string()))
+                .collect();
+            assert_eq!(nodes.join(","), registered, "{:?} {}", policy, body);
+        }
+    }
+
+    #[actix_web::test]
+    async fn dual_stack_node_is_a_single_node() {
+        let (app_data, store) = app_data(test_config());
+        let app = test::init_service(
+            App::new()
+                .configure(app_data)
+                .service(register)
+                .service(deregister)
+                .service(query),
+        )
+        .await;
+
+        // registering the IPv6 address of an IPv4-only node makes it dual-stack
+        for body in [
+            serde_json::json!({"ipv4_address": "93.184.216.34", "port": 8333}),
+            serde_json::json!({"ipv4_address": "93.184.216.34", "ipv6_address": "2606:2800:220:1::1", "port": 8333}),
+        ] {
+            let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+            assert!(test::call_service(&app, request).await.status().is_success());
+        }
+        assert_eq!(store.list().len(), 1);
+
+        let request = test::TestRequest::get().uri("/query?family=v6").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body["nodes"][0]["ipv4_address"], "93.184.216.34");
+        assert_eq!(body["nodes"][0]["ipv6_address"], "2606:2800:220:1::1");
+
+        // a refresh over a single family keeps the address of the other family
+        let body = serde_json::json!({"ipv4_address": "93.184.216.34", "port": 8333});
+        let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        assert_eq!(store.list()[0].ipv6_address, Some("2606:2800:220:1::1".parse().unwrap()));
+
+        // either address deregisters the node
+        let body = serde_json::json!({"ipv6_address": "2606:2800:220:1::1", "port": 8333});
+        let request = test::TestRequest::post().uri("/deregister").set_json(&body).to_request();
+        assert!(test::call_service(&app, request).await.status().is_success());
+        assert_eq!(store.list().len(), 1);
+        assert!(store.list()[0].inactive_since.is_some());
+    }
+
+    #[actix_web::test]
+    async fn signed_node_can_only_be_updated_with_its_key() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let (app_data, store) = app_data(test_config());
+        let app = test::init_service(
+            App::new()
+                .configure(app_data)
+                .service(register)
+                .service(deregister),
+        )
+        .await;
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let public_key = hex::encode(key.verifying_key().as_bytes());
+        let signed = |action: Action, nonce: &str| {
+            let mut body = serde_json::json!({
+                "ipv4_address": "93.184.216.34",
+                "port": 8333,
+                "public_key": public_key,
+                "timestamp": now(),
+                "nonce": nonce,
+            });
+            let request: NodeRequest = serde_json::from_value(body.clone()).unwrap();
+            let message = signature::canonical_message(action, &request);
+            body["signature"] = hex::encode(key.sign(message.as_bytes()).to_bytes()).into();
+            body
+        };
+
+        let body = signed(Action::Register, "1");
+        let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED);
+        assert_eq!(store.list()[0].public_key.as_deref(), Some(public_key.as_str()));
+
+        // the same signed request cannot be replayed
+        let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+
+        // a node bound to a key cannot be deregistered without a signature of that key
+        let unsigned = serde_json::json!({"ipv4_address": "93.184.216.34", "port": 8333});
+        let request = test::TestRequest::post().uri("/deregister").set_json(&unsigned).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(store.list().len(), 1);
+
+        let body = signed(Action::Deregister, "2");
+        let request = test::TestRequest::post().uri("/deregister").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        assert!(store.list()[0].inactive_since.is_some());
+    }
+
+    #[actix_web::test]
+    async fn dual_stack_request_cannot_take_over_a_signed_node() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let (app_data, store) = app_data(test_config());
+        let app = test::init_service(
+            App::new()
+                .configure(app_data)
+                .service(register)
+                .service(deregister),
+        )
+        .await;
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let public_key = hex::encode(key.verifying_key().as_bytes());
+        let ipv6_address = "2606:2800:220:1:248:1893:25c8:1946";
+
+        // an unsigned node comes first, so that it is the first node matching the dual-stack address
+        let attacker = serde_json::json!({"ipv4_address": "93.184.216.34", "port": 8333});
+        let request = test::TestRequest::post().uri("/register").set_json(&attacker).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED);
+        let mut victim = serde_json::json!({
+            "ipv6_address": ipv6_address,
+            "port": 8333,
+            "public_key": public_key,
+            "timestamp": now(),
+            "nonce": "1",
+        });
+        let request: NodeRequest = serde_json::from_value(victim.clone()).unwrap();
+        let message = signature::canonical_message(Action::Register, &request);
+        victim["signature"] = hex::encode(key.sign(message.as_bytes()).to_bytes()).into();
+        let request = test::TestRequest::post().uri("/register").set_json(&victim).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED);
+
+        // an unsigned request naming both addresses would act on the signed node too
+        let dual = serde_json::json!({"ipv4_address": "93.184.216.34", "ipv6_address": ipv6_address, "port": 8333});
+        for uri in ["/register", "/deregister"] {
+            let request = test::TestRequest::post().uri(uri).set_json(&dual).to_request();
+            assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN, "{}", uri);
+        }
+        let nodes = store.list();
+        assert_eq!(nodes.len(), 2);
+        let signed = nodes.iter().find(|node| node.ipv6_address.is_some()).unwrap();
+        assert_eq!(signed.public_key.as_deref(), Some(public_key.as_str()));
+        assert!(nodes.iter().all(|node| node.inactive_since.is_none()));
+    }
+
+    #[actix_web::test]
+    async fn new_node_must_solve_a_challenge() {
+        let (app_data, store) = app_data(Config {
+            pow_enabled: true,
+            pow_difficulty: 8,
+            ..test_config()
+        });
+        let app = test::init_service(
+            App::new()
+                .configure(app_data)
+                .service(register_challenge)
+                .service(register),
+        )
+        .await;
+        let body = serde_json::json!({"ipv4_address": "93.184.216.34", "port": 8333});
+
+        let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let error: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(error["error"], "invalid_proof_of_work");
+
+        let solve = || async {
+            let request = test::TestRequest::get().uri("/register/challenge").to_request();
+            let challenge: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+            assert_eq!(challenge["difficulty"], 8);
+            let challenge = challenge["challenge"].as_str().unwrap().to_string();
+            let solution = (0u64..)
+                .map(|counter| counter.to_string())
+                .find(|solution| pow::leading_zero_bits(&pow::hash(&challenge, solution)) >= 8)
+                .unwrap();
+            let mut solved = body.clone();
+            solved["pow_challenge"] = challenge.into();
+            solved["pow_solution"] = solution.into();
+            solved
+        };
+
+        let mut solved = solve().await;
+        let request = test::TestRequest::post().uri("/register").set_json(&solved).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED);
+
+        // refreshing the node is free, but the challenge cannot register another node
+        let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        solved["port"] = 8334.into();
+        let request = test::TestRequest::post().uri("/register").set_json(&solved).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(store.list().len(), 1);
+
+        // a node that expired without being marked inactive yet pays again, and is created again
+        let address = store.list()[0].addr();
+        store.update(&address, &mut |node| node.last_seen = 0);
+        let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+        let request = test::TestRequest::post().uri("/register").set_json(&solve().await).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::CREATED);
+    }
+
+    #[actix_web::test]
+    async fn registrations_per_subnet_are_capped() {
+        let (app_data, store) = app_data(Config {
+            max_nodes_per_ipv4_subnet: 2,
+            ..test_config()
+        });
+        let app = test::init_service(App::new().configure(app_data).service(register)).await;
+
+        for (address, port, status) in [
+            ("93.184.216.34", 8333, StatusCode::CREATED),
+            ("93.184.1.1", 8333, StatusCode::CREATED),
+            // a third node in 93.184.0.0/16
+            ("93.184.2.2", 8333, StatusCode::FORBIDDEN),
+            // refreshing a node of a full subnet is fine
+            ("93.184.216.34", 8333, StatusCode::OK),
+            ("198.41.0.4", 8333, StatusCode::CREATED),
+        ] {
+            let body = serde_json::json!({"ipv4_address": address, "port": port});
+            let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+            assert_eq!(test::call_service(&app, request).await.status(), status, "{}", address);
+        }
+        assert_eq!(store.list().len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn clients_over_budget_are_told_to_retry_later() {
+        let config = Config {
+            rate_limit_enabled: true,
+            rate_limit_register_per_min: 2,
+            rate_limit_allowlist: vec!["198.41.0.0/24".parse().unwrap()],
+            ..test_config()
+        };
+        let limiter = Arc::new(RateLimiter::new(&config));
+        let (app_data, _) = app_data(config);
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(limiter, Vec::new()))
+                .configure(app_data)
+                .service(register)
+                .service(query),
+        )
+        .await;
+        let registration = |peer: &str| {
+            let body = serde_json::json!({"port": 8333});
+            test::TestRequest::post()
+                .uri("/register")
+                .peer_addr(peer.parse().unwrap())
+                .set_json(body)
+                .to_request()
+        };
+
+        assert!(test::call_service(&app, registration("93.184.216.34:50000")).await.status().is_success());
+        assert!(test::call_service(&app, registration("93.184.216.34:50001")).await.status().is_success());
+        let response = test::call_service(&app, registration("93.184.216.34:50002")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after = response.headers().get("Retry-After").unwrap().to_str().unwrap();
+        assert_eq!(retry_after, "30");
+
+        // the other endpoints and the allowlisted clients have their own budget
+        let request = test::TestRequest::get()
+            .uri("/query")
+            .peer_addr("93.184.216.34:50003".parse().unwrap())
+            .to_request();
+        assert!(test::call_service(&app, request).await.status().is_success());
+        for port in 0..5 {
+            let peer = format!("198.41.0.4:{}", 50000 + port);
+            assert!(test::call_service(&app, registration(&peer)).await.status().is_success());
+        }
+    }
+
+    #[actix_web::test]
+    async fn every_response_carries_a_request_id() {
+        let app = test::init_service(App::new().wrap(RequestTrace).service(index)).await;
+
+        let request = test::TestRequest::get().uri("/").insert_header(("X-Request-Id", "abc-1")).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.headers().get("X-Request-Id").unwrap(), "abc-1");
+
+        // an unusable ID is replaced
+        let request = test::TestRequest::get().uri("/").insert_header(("X-Request-Id", "a b")).to_request();
+        let response = test::call_service(&app, request).await;
+        let request_id = response.headers().get("X-Request-Id").unwrap().to_str().unwrap();
+        assert_eq!(request_id.len(), 16);
+    }
+
+    #[actix_web::test]
+    async fn metrics_count_the_nodes_and_the_events() {
+        let (app_data, _) = app_data(test_config());
+        let latencies = Arc::new(Metrics::default());
+        let app = test::init_service(
+            App::new()
+                .wrap(RecordMetrics::new(latencies.clone()))
+                .configure(app_data)
+                .service(register)
+                .service(query)
+                .service(export_metrics),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri("/query").to_request();
+        test::call_service(&app, request).await;
+        let body = serde_json::json!({"ipv4_address": "93.184.216.34", "ipv6_address": "2606:2800:220:1::1", "port": 8333});
+        let request = test::TestRequest::post().uri("/register").set_json(&body).to_request();
+        test::call_service(&app, request).await;
+        let request = test::TestRequest::get().uri("/query").to_request();
+        test::call_service(&app, request).await;
+
+        let request = test::TestRequest::get().uri("/metrics").to_request();
+        let body = test::call_and_read_body(&app, request).await;
+        let text = std::str::from_utf8(&body).unwrap();
+        for line in [
+            r#"rusty_coin_dns_nodes{network="mainnet",family="v4",state="active"} 1"#,
+            r#"rusty_coin_dns_nodes{network="mainnet",family="v6",state="active"} 1"#,
+            r#"rusty_coin_dns_nodes{network="mainnet",family="v4",state="stale"} 0"#,
+            r#"rusty_coin_dns_nodes{network="mainnet",family="v4",state="inactive"} 0"#,
+            r#"rusty_coin_dns_events_total{event="registered"} 1"#,
+            r#"rusty_coin_dns_events_total{event="query_served"} 1"#,
+            r#"rusty_coin_dns_events_total{event="query_empty"} 1"#,
+        ] {
+            assert!(text.lines().any(|metric| metric == line), "{} missing in\n{}", line, text);
+        }
+
+        let diversity = Diversity::from_config(&test_config()).unwrap();
+        let network = NetworkState::new("mainnet", test_config(), Arc::new(MemoryStore::default()), diversity);
+        let text = latencies.render(&Networks::new(vec![network]), now());
+        for line in [
+            r#"rusty_coin_dns_http_responses_total{endpoint="/query",status="200"} 1"#,
+            r#"rusty_coin_dns_http_responses_total{endpoint="/query",status="404"} 1"#,
+            r#"rusty_coin_dns_http_request_duration_seconds_count{endpoint="/register"} 1"#,
+            r#"rusty_coin_dns_http_request_duration_seconds_bucket{endpoint="/query",le="+Inf"} 2"#,
+        ] {
+            assert!(text.lines().any(|metric| metric == line), "{} missing in\n{}", line, text);
+        }
+    }
+
+    #[actix_web::test]
+    async fn dns_only_serves_the_nodes_on_the_default_port() {
+        let store = MemoryStore::new(vec![
+            test_node("93.184.216.34", 8333, now()),
+            test_node("93.184.216.35", 18333, now()),
+            Node { inactive_since: Some(now()), ..test_node("93.184.216.36", 8333, now()) },
+        ]);
+        let config = test_config();
+        let diversity = Diversity::from_config(&config).unwrap();
+        let network = NetworkState::new("mainnet", config, Arc::new(store), diversity);
+        assert_eq!(dns_peers(&network, 8333, now()), [IpAddr::from([93, 184, 216, 34])]);
+        assert_eq!(dns_peers(&network, 18333, now()), [IpAddr::from([93, 184, 216, 35])]);
+    }
 }
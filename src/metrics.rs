@@ -0,0 +1,274 @@
+//! metrics of the DNS server, served by `GET /metrics` in the Prometheus text format
+//! - `rusty_coin_dns_nodes{network, family, state}`: the registered nodes by network, address
+//!   family (`v4`, `v6`, a dual-stack node counts in both) and lifecycle state (`active`, `stale`
+//!   or `inactive`)
+//! - `rusty_coin_dns_events_total{event}`: registrations, refreshes, deregistrations,
+//!   expirations, purges, removals by the operators, queries (with or without nodes) and probe outcomes
+//! - `rusty_coin_dns_http_responses_total{endpoint, status}`: the HTTP responses
+//! - `rusty_coin_dns_http_request_duration_seconds{endpoint}`: a histogram of the time
+//!   spent handling the requests, store locks included
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::{ready, Ready};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready};
+use futures_util::future::LocalBoxFuture;
+use crate::network::Networks;
+use crate::node::{Family, NodeState};
+
+/// the upper bounds (in seconds) of the buckets of the latency histograms
+const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// the events counted by `rusty_coin_dns_events_total`
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    /// a new node was registered
+    Registered,
+    /// a registered node registered again
+    Refreshed,
+    Heartbeat,
+    Deregistered,
+    /// a node became inactive because its TTL and grace period lapsed
+    Expired,
+    /// an inactive node was forgotten after the retention period
+    Purged,
+    /// an operator removed a node, or banned it, see the `admin` module
+    Removed,
+    /// `/query` returned at least one node
+    QueryServed,
+    /// `/query` found no active node
+    QueryEmpty,
+    ProbeSucceeded,
+    ProbeFailed,
+}
+
+impl Event {
+    const ALL: [Event; 11] = [
+        Event::Registered,
+        Event::Refreshed,
+        Event::Heartbeat,
+        Event::Deregistered,
+        Event::Expired,
+        Event::Purged,
+        Event::Removed,
+        Event::QueryServed,
+        Event::QueryEmpty,
+        Event::ProbeSucceeded,
+        Event::ProbeFailed,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Event::Registered => "registered",
+            Event::Refreshed => "refreshed",
+            Event::Heartbeat => "heartbeat",
+            Event::Deregistered => "deregistered",
+            Event::Expired => "expired",
+            Event::Purged => "purged",
+            Event::Removed => "removed",
+            Event::QueryServed => "query_served",
+            Event::QueryEmpty => "query_empty",
+            Event::ProbeSucceeded => "probe_succeeded",
+            Event::ProbeFailed => "probe_failed",
+        }
+    }
+}
+
+/// a latency histogram
+#[derive(Debug, Default)]
+struct Histogram {
+    /// the number of observations per bucket (not cumulative), the last one is `+Inf`
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// the counters and histograms, shared by the handlers, the middleware and the background tasks
+#[derive(Default)]
+pub struct Metrics {
+    /// indexed by the position of the event in `Event::ALL`
+    events: [AtomicU64; Event::ALL.len()],
+    /// the number of responses per endpoint and status code
+    responses: Mutex<BTreeMap<(String, u16), u64>>,
+    /// the latency histogram per endpoint
+    latencies: Mutex<BTreeMap<String, Histogram>>,
+}
+
+impl Metrics {
+    pub fn record(&self, event: Event) {
+        self.events[event as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// record a response of `endpoint` (a route pattern, so that the labels are bounded)
+    fn observe_response(&self, endpoint: &str, status: u16, seconds: f64) {
+        *self
+            .responses
+            .lock()
+            .unwrap()
+            .entry((endpoint.to_string(), status))
+            .or_default() += 1;
+        self.latencies
+            .lock()
+            .unwrap()
+            .entry(endpoint.to_string())
+            .or_default()
+            .observe(seconds);
+    }
+
+    /// the metrics in the Prometheus text format, with the node gauges computed from the nodes
+    /// of `networks`
+    pub fn render(&self, networks: &Networks, now: u64) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP rusty_coin_dns_nodes The registered nodes by network, address family and state.\n");
+        out.push_str("# TYPE rusty_coin_dns_nodes gauge\n");
+        for network in networks.iter() {
+            let nodes = network.store.list();
+            for (family, label) in [(Family::V4, "v4"), (Family::V6, "v6")] {
+                let states: Vec<NodeState> = nodes
+                    .iter()
+                    .filter(|node| node.has_family(family))
+                    .map(|node| node.state(now, &network.config))
+                    .collect();
+                for (state, state_label) in [
+                    (NodeState::Active, "active"),
+                    (NodeState::Stale, "stale"),
+                    (NodeState::Inactive, "inactive"),
+                ] {
+                    let count = states.iter().filter(|node_state| **node_state == state).count();
+                    let _ = writeln!(
+                        out,
+                        "rusty_coin_dns_nodes{{network=\"{}\",family=\"{}\",state=\"{}\"}} {}",
+                        network.name, label, state_label, count
+                    );
+                }
+            }
+        }
+
+        out.push_str("# HELP rusty_coin_dns_events_total The node events since startup.\n");
+        out.push_str("# TYPE rusty_coin_dns_events_total counter\n");
+        for (event, count) in Event::ALL.iter().zip(&self.events) {
+            let _ = writeln!(
+                out,
+                "rusty_coin_dns_events_total{{event=\"{}\"}} {}",
+                event.label(),
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# HELP rusty_coin_dns_http_responses_total The HTTP responses by endpoint and status.\n");
+        out.push_str("# TYPE rusty_coin_dns_http_responses_total counter\n");
+        for ((endpoint, status), count) in self.responses.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "rusty_coin_dns_http_responses_total{{endpoint=\"{}\",status=\"{}\"}} {}",
+                endpoint, status, count
+            );
+        }
+
+        out.push_str("# HELP rusty_coin_dns_http_request_duration_seconds The time spent handling the requests.\n");
+        out.push_str("# TYPE rusty_coin_dns_http_request_duration_seconds histogram\n");
+        for (endpoint, histogram) in self.latencies.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            let bounds = LATENCY_BUCKETS.iter().map(|bound| bound.to_string()).chain(["+Inf".to_string()]);
+            for (bound, count) in bounds.zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "rusty_coin_dns_http_request_duration_seconds_bucket{{endpoint=\"{}\",le=\"{}\"}} {}",
+                    endpoint, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "rusty_coin_dns_http_request_duration_seconds_sum{{endpoint=\"{}\"}} {}",
+                endpoint, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "rusty_coin_dns_http_request_duration_seconds_count{{endpoint=\"{}\"}} {}",
+                endpoint, histogram.count
+            );
+        }
+
+        out
+    }
+}
+
+/// the middleware recording the status and the latency of every response
+pub struct RecordMetrics {
+    metrics: Arc<Metrics>,
+}
+
+impl RecordMetrics {
+    pub fn new(metrics: Arc<Metrics>) -> RecordMetrics {
+        RecordMetrics { metrics }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RecordMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RecordMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RecordMetricsMiddleware {
+            service,
+            metrics: self.metrics.clone(),
+        }))
+    }
+}
+
+pub struct RecordMetricsMiddleware<S> {
+    service: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S, B> Service<ServiceRequest> for RecordMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let metrics = self.metrics.clone();
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let response = response.await?;
+            // the route pattern rather than the path, which could be anything
+            let endpoint = response
+                .request()
+                .match_pattern()
+                .unwrap_or_else(|| "unmatched".to_string());
+            metrics.observe_response(&endpoint, response.status().as_u16(), started.elapsed().as_secs_f64());
+            Ok(response)
+        })
+    }
+}
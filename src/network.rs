@@ -0,0 +1,105 @@
+//! the networks served by the DNS server (e.g. `mainnet`, `testnet` and `regtest`)
+//! every network has its own nodes, store, limits and TTLs, so that the nodes of a test network
+//! are never handed out to the clients of another network
+//! - the node endpoints of a network are served under `/v1/<network>`, e.g. `/v1/testnet/query`
+//!   (and under `/<network>` by the legacy API, see the `api` module)
+//! - the endpoints without a prefix serve the default network, the first configured one
+//! - a request for a network that is not configured is answered with 404 Not Found
+//!   and `{"error": "unknown_network", "message": "..."}`
+//!
+//! the settings of a network are the global settings, overridden by its own ones,
+//! see `Config::network`
+
+use std::future::{ready, Ready};
+use std::ops::Deref;
+use std::sync::Arc;
+use actix_web::{dev, web, FromRequest, HttpRequest};
+use crate::api::ApiError;
+use crate::config::Config;
+use crate::diversity::Diversity;
+use crate::pow::PowGuard;
+use crate::store::NodeStore;
+
+/// the state of a network
+pub struct NetworkState {
+    pub name: String,
+    /// the settings of the network
+    pub config: Config,
+    pub store: Arc<dyn NodeStore>,
+    pub pow_guard: PowGuard,
+    pub diversity: Diversity,
+}
+
+impl NetworkState {
+    pub fn new(name: &str, config: Config, store: Arc<dyn NodeStore>, diversity: Diversity) -> NetworkState {
+        NetworkState {
+            name: name.to_string(),
+            pow_guard: PowGuard::new(&config),
+            config,
+            store,
+            diversity,
+        }
+    }
+}
+
+/// all the networks, registered as app data
+pub struct Networks {
+    /// in the configured order, the first one is the default network
+    networks: Vec<Arc<NetworkState>>,
+}
+
+impl Networks {
+    /// `networks` must not be empty
+    pub fn new(networks: Vec<NetworkState>) -> Networks {
+        assert!(!networks.is_empty(), "at least one network is required");
+        Networks {
+            networks: networks.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<NetworkState>> {
+        self.networks.iter().find(|network| network.name == name)
+    }
+
+    /// the network of the endpoints without a prefix
+    pub fn default_network(&self) -> &Arc<NetworkState> {
+        &self.networks[0]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<NetworkState>> {
+        self.networks.iter()
+    }
+}
+
+/// the network a request is about: the `{network}` segment of its path,
+/// or the default network if the route has none
+#[derive(Clone)]
+pub struct Network(Arc<NetworkState>);
+
+impl Deref for Network {
+    type Target = NetworkState;
+
+    fn deref(&self) -> &NetworkState {
+        &self.0
+    }
+}
+
+impl FromRequest for Network {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Network, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        let networks = match req.app_data::<web::Data<Networks>>() {
+            Some(networks) => networks,
+            None => {
+                let err = ApiError::Internal("the networks are not configured".to_string());
+                return ready(Err(err.into()));
+            }
+        };
+        let network = match req.match_info().get("network") {
+            Some(name) => networks.get(name).ok_or_else(|| ApiError::UnknownNetwork(name.to_string())),
+            None => Ok(networks.default_network()),
+        };
+        ready(network.map(|network| Network(network.clone())).map_err(Into::into))
+    }
+}
@@ -0,0 +1,224 @@
+//! the nodes registered with the DNS server
+//! a node goes through the following states:
+//! - active: it registered or sent a heartbeat within its TTL, it is handed out to clients
+//!   (as long as it passes its health probes)
+//! - stale: its TTL lapsed, it is no longer handed out but a heartbeat brings it back
+//! - inactive: it deregistered, or it stayed stale for the grace period, it must register again,
+//!   until then it is only kept for recovery (listed, and handed out if no node is active)
+//! - purged: it stayed inactive for the retention period and is forgotten
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use crate::config::Config;
+use crate::probe::Health;
+
+/// a node, reachable on its IPv4 address, its IPv6 address or both
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct Node {
+    /// a node in the network
+    /// and its IP addresses, IPv4, IPv6 or both (at least one of them is set)
+    /// a dual-stack node is a single node reachable on both addresses
+    pub ipv4_address: Option<Ipv4Addr>,
+    pub ipv6_address: Option<Ipv6Addr>,
+    /// and a port, the same on both addresses
+    #[schemars(range(min = 1))]
+    pub port: u16,
+    /// the Ed25519 public key (hex-encoded) the node is bound to, if it signed its registration
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// the last time (UNIX timestamp in seconds) the node registered or sent a heartbeat,
+    /// set by the server and ignored in request bodies
+    /// (nodes loaded from the storage on startup get a fresh TTL)
+    #[serde(default, skip_deserializing)]
+    pub last_seen: u64,
+    /// the results of the health probes of the node,
+    /// set by the server and ignored in request bodies
+    #[serde(default, skip_deserializing)]
+    pub health: Health,
+    /// what the node says about itself
+    #[serde(flatten)]
+    pub metadata: Metadata,
+    /// the time (UNIX timestamp in seconds) the node became inactive, if it is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inactive_since: Option<u64>,
+}
+
+/// the metadata a node reports when it registers, so that clients can pick compatible peers
+/// it is self-reported and not verified by the server
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub struct Metadata {
+    /// the protocol version the node speaks
+    #[serde(default)]
+    pub protocol_version: Option<u32>,
+    /// the bit field of the services the node offers
+    #[serde(default)]
+    pub services: u64,
+    /// the user agent of the node software, e.g. `/rusty_coin:0.1.0/`
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    /// the height of the best block the node knows of, updated by its heartbeats
+    #[serde(default)]
+    pub best_height: Option<u64>,
+}
+
+/// the lifecycle state of a node, see the module documentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeState {
+    Active,
+    Stale,
+    Inactive,
+}
+
+/// the address family of a node address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Family {
+    V4,
+    V6,
+    /// either family
+    #[default]
+    Any,
+}
+
+/// the addresses a node is registered at, which identify it
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NodeAddr {
+    #[serde(default)]
+    pub ipv4_address: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub ipv6_address: Option<Ipv6Addr>,
+    pub port: u16,
+}
+
+impl NodeAddr {
+    /// whether `node` is registered at this port and at either of these addresses
+    pub fn matches(&self, node: &Node) -> bool {
+        let ipv4 = self.ipv4_address.is_some() && self.ipv4_address == node.ipv4_address;
+        let ipv6 = self.ipv6_address.is_some() && self.ipv6_address == node.ipv6_address;
+        node.port == self.port && (ipv4 || ipv6)
+    }
+}
+
+impl fmt::Display for NodeAddr {
+    /// `<IPv4>:<port>`, `[<IPv6>]:<port>` or both separated by a slash
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ipv4) = self.ipv4_address {
+            write!(f, "{}", SocketAddr::new(IpAddr::V4(ipv4), self.port))?;
+            if self.ipv6_address.is_some() {
+                write!(f, "/")?;
+            }
+        }
+        if let Some(ipv6) = self.ipv6_address {
+            write!(f, "{}", SocketAddr::new(IpAddr::V6(ipv6), self.port))?;
+        }
+        Ok(())
+    }
+}
+
+impl Node {
+    /// the addresses the node is registered at
+    pub fn addr(&self) -> NodeAddr {
+        NodeAddr {
+            ipv4_address: self.ipv4_address,
+            ipv6_address: self.ipv6_address,
+            port: self.port,
+        }
+    }
+
+    /// whether the node has an address of the given family
+    pub fn has_family(&self, family: Family) -> bool {
+        match family {
+            Family::V4 => self.ipv4_address.is_some(),
+            Family::V6 => self.ipv6_address.is_some(),
+            Family::Any => true,
+        }
+    }
+
+    /// take the metadata and the last-seen timestamp of a new registration of the same node,
+    /// which also makes an inactive node active again, the probe results are kept
+    /// a registration with the address of one family only keeps the address of the other family,
+    /// e.g. a dual-stack node refreshed over IPv4 stays dual-stack
+    pub fn refresh(&mut self, registration: Node) {
+        let health = std::mem::take(&mut self.health);
+        let (ipv4_address, ipv6_address) = (self.ipv4_address, self.ipv6_address);
+        *self = registration;
+        self.health = health;
+        self.ipv4_address = self.ipv4_address.or(ipv4_address);
+        self.ipv6_address = self.ipv6_address.or(ipv6_address);
+    }
+
+    /// the IP addresses of the node, IPv4 first
+    pub fn ip_addresses(&self) -> Vec<IpAddr> {
+        let ipv4 = self.ipv4_address.map(IpAddr::V4);
+        let ipv6 = self.ipv6_address.map(IpAddr::V6);
+        ipv4.into_iter().chain(ipv6).collect()
+    }
+
+    /// whether the node has registered or sent a heartbeat within the last `ttl` seconds
+    pub fn is_alive(&self, now: u64, ttl: u64) -> bool {
+        now.saturating_sub(self.last_seen) < ttl
+    }
+
+    /// the lifecycle state of the node at `now`,
+    /// a node stale for longer than the grace period is inactive even before the reaper marks it
+    pub fn state(&self, now: u64, config: &Config) -> NodeState {
+        if self.inactive_since.is_some()
+            || !self.is_alive(now, config.node_ttl_secs.saturating_add(config.stale_grace_secs))
+        {
+            NodeState::Inactive
+        } else if self.is_alive(now, config.node_ttl_secs) {
+            NodeState::Active
+        } else {
+            NodeState::Stale
+        }
+    }
+
+    /// whether the node can be handed out to clients:
+    /// it is active and, if probing is enabled, it passed its recent probes
+    pub fn is_active(&self, now: u64, config: &Config) -> bool {
+        self.state(now, config) == NodeState::Active
+            && (!config.probe_enabled || self.health.is_healthy(config.probe_failure_threshold))
+    }
+
+    /// whether the node is stale or inactive, but was last seen within the fallback window,
+    /// so that it can be handed out if no node is active
+    pub fn is_recently_inactive(&self, now: u64, config: &Config) -> bool {
+        self.state(now, config) != NodeState::Active
+            && self.is_alive(now, config.query_fallback_window_secs)
+    }
+
+    /// the address the health probes connect to, the IPv4 address of a dual-stack node
+    pub fn probe_address(&self) -> Option<SocketAddr> {
+        self.ip_addresses()
+            .first()
+            .map(|address| SocketAddr::new(*address, self.port))
+    }
+}
+
+/// current UNIX timestamp in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// an IPv4-only node last seen at `last_seen`, for the tests
+#[cfg(test)]
+pub fn test_node(ipv4: &str, port: u16, last_seen: u64) -> Node {
+    Node {
+        ipv4_address: Some(ipv4.parse().unwrap()),
+        ipv6_address: None,
+        port,
+        public_key: None,
+        last_seen,
+        health: Health::default(),
+        metadata: Metadata::default(),
+        inactive_since: None,
+    }
+}
@@ -0,0 +1,352 @@
+//! the OpenAPI 3 description of the HTTP API, served by `GET /openapi.json`
+//! - the schemas under `#/components/schemas` are derived (with `schemars`) from the types the
+//!   handlers actually take and return, with the names and the field docs of those types
+//! - the node endpoints are described once, and listed under `/v1` for the default network and
+//!   under `/v1/{network}` for every network, the legacy routes without a version are left out
+//!   (see the `api` module)
+//! - the tests of the handlers check that every operation is served, and that the bodies the
+//!   handlers return match the schemas, so that the document cannot drift from the code
+
+use schemars::generate::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+use crate::api::{self, ErrorBody, LeaseResponse, MessageResponse, NodesResponse};
+use crate::node::Node;
+use crate::pow::Challenge;
+use crate::validation::NodeRequest;
+use crate::InactiveNode;
+
+/// the schemas of the bodies, the bodies the server writes have all their serialized fields,
+/// the bodies it reads have the fields it deserializes
+struct Schemas {
+    responses: SchemaGenerator,
+    requests: SchemaGenerator,
+}
+
+impl Schemas {
+    fn new() -> Schemas {
+        Schemas {
+            responses: SchemaSettings::openapi3().for_serialize().into_generator(),
+            requests: SchemaSettings::openapi3().for_deserialize().into_generator(),
+        }
+    }
+
+    /// a reference to the schema of the response body `T`
+    fn response<T: JsonSchema>(&mut self) -> Value {
+        self.responses.subschema_for::<T>().to_value()
+    }
+
+    /// a reference to the schema of the request body `T`
+    fn request<T: JsonSchema>(&mut self) -> Value {
+        self.requests.subschema_for::<T>().to_value()
+    }
+
+    /// a reference to the schema of the error body
+    fn error(&mut self) -> Value {
+        self.response::<ErrorBody>()
+    }
+
+    /// all the schemas referenced so far, by name
+    fn into_components(mut self) -> Map<String, Value> {
+        let mut schemas = self.responses.take_definitions(true);
+        schemas.extend(self.requests.take_definitions(true));
+        schemas
+    }
+}
+
+/// an unsigned integer
+fn integer() -> Value {
+    json!({ "type": "integer", "minimum": 0 })
+}
+
+/// a node endpoint
+struct Operation {
+    method: &'static str,
+    /// the path of the route, without the version and the network
+    path: &'static str,
+    summary: &'static str,
+    parameters: Vec<Value>,
+    request_body: Option<Value>,
+    /// the status codes, their descriptions and their bodies
+    responses: Vec<(u16, &'static str, Value)>,
+}
+
+/// a parameter of the query string
+fn query_parameter(name: &str, description: &str, schema: Value) -> Value {
+    json!({ "name": name, "in": "query", "required": false, "description": description, "schema": schema })
+}
+
+/// the body of the node endpoints taking a node
+fn node_request(schemas: &mut Schemas) -> Value {
+    json!({ "required": true, "content": { "application/json": { "schema": schemas.request::<NodeRequest>() } } })
+}
+
+/// the node endpoints, see the documentation of the handlers
+fn node_operations(schemas: &mut Schemas) -> Vec<Operation> {
+    vec![
+        Operation {
+            method: "get",
+            path: "/register/challenge",
+            summary: "hand out a proof-of-work challenge for a new registration",
+            parameters: Vec::new(),
+            request_body: None,
+            responses: vec![
+                (200, "a challenge", schemas.response::<Challenge>()),
+                (404, "`pow_disabled` or `unknown_network`", schemas.error()),
+                (503, "`too_many_challenges`", schemas.error()),
+            ],
+        },
+        Operation {
+            method: "post",
+            path: "/register",
+            summary: "register a node, or refresh its registration",
+            parameters: Vec::new(),
+            request_body: Some(node_request(schemas)),
+            responses: vec![
+                (200, "the node was already registered", schemas.response::<LeaseResponse>()),
+                (201, "the node is registered", schemas.response::<LeaseResponse>()),
+                (400, "`invalid_field` or `invalid_body`", schemas.error()),
+                (401, "`invalid_signature`", schemas.error()),
+                (403, "`key_mismatch`, `invalid_proof_of_work`, `group_full` or `banned`", schemas.error()),
+                (404, "`unknown_network`", schemas.error()),
+                (500, "`internal_error`", schemas.error()),
+            ],
+        },
+        Operation {
+            method: "post",
+            path: "/heartbeat",
+            summary: "tell that a registered node is still alive",
+            parameters: Vec::new(),
+            request_body: Some(node_request(schemas)),
+            responses: vec![
+                (200, "the lease of the node is renewed", schemas.response::<LeaseResponse>()),
+                (400, "`invalid_field` or `invalid_body`", schemas.error()),
+                (401, "`invalid_signature`", schemas.error()),
+                (403, "`key_mismatch` or `banned`", schemas.error()),
+                (404, "`node_not_found` or `unknown_network`", schemas.error()),
+                (500, "`internal_error`", schemas.error()),
+            ],
+        },
+        Operation {
+            method: "post",
+            path: "/deregister",
+            summary: "deregister a node, it is kept as inactive until it is purged",
+            parameters: Vec::new(),
+            request_body: Some(node_request(schemas)),
+            responses: vec![
+                (200, "the node is deregistered", schemas.response::<MessageResponse>()),
+                (400, "`invalid_field` or `invalid_body`", schemas.error()),
+                (401, "`invalid_signature`", schemas.error()),
+                (403, "`key_mismatch`", schemas.error()),
+                (404, "`unknown_network`", schemas.error()),
+                (500, "`internal_error`", schemas.error()),
+            ],
+        },
+        Operation {
+            method: "get",
+            path: "/query",
+            summary: "poll distinct active nodes at random, or recently seen ones if none is active \
+                      (with an `X-Node-Pool: inactive` header)",
+            parameters: vec![
+                query_parameter(
+                    "count",
+                    "the number of nodes wanted, capped by the server",
+                    json!({ "type": "integer", "minimum": 1, "default": 1 }),
+                ),
+                query_parameter(
+                    "family",
+                    "only the nodes with an address of this family",
+                    json!({ "type": "string", "enum": ["v4", "v6", "any"], "default": "any" }),
+                ),
+                query_parameter("min_version", "the minimum protocol version of the nodes", integer()),
+                query_parameter("services", "the service bits the nodes must all offer", integer()),
+                query_parameter("min_height", "the minimum best block height of the nodes", integer()),
+            ],
+            request_body: None,
+            responses: vec![
+                (200, "the nodes", schemas.response::<NodesResponse<Node>>()),
+                (400, "`invalid_query`", schemas.error()),
+                (404, "`no_nodes` or `unknown_network`", schemas.error()),
+            ],
+        },
+        Operation {
+            method: "get",
+            path: "/nodes/inactive",
+            summary: "list the stale and inactive nodes that have not been purged yet",
+            parameters: Vec::new(),
+            request_body: None,
+            responses: vec![
+                (200, "the nodes", schemas.response::<NodesResponse<InactiveNode>>()),
+                (404, "`unknown_network`", schemas.error()),
+            ],
+        },
+    ]
+}
+
+/// the responses of an operation, every node endpoint is rate-limited
+fn responses(operation: &Operation, schemas: &mut Schemas) -> Value {
+    let mut responses = Map::new();
+    let rate_limited = (429, "`rate_limited`, see the `Retry-After` header", schemas.error());
+    for (status, description, schema) in operation.responses.iter().chain([&rate_limited]) {
+        responses.insert(
+            status.to_string(),
+            json!({ "description": description, "content": { "application/json": { "schema": schema } } }),
+        );
+    }
+    Value::Object(responses)
+}
+
+/// the OpenAPI document of the HTTP API
+pub fn document() -> Value {
+    let mut schemas = Schemas::new();
+    let mut paths = Map::new();
+    paths.insert(
+        "/".to_string(),
+        json!({ "get": {
+            "summary": "test the server is running",
+            "responses": { "200": { "description": "`Hello World!`",
+                "content": { "application/json": { "schema": schemas.response::<MessageResponse>() } } } },
+        } }),
+    );
+    paths.insert(
+        "/metrics".to_string(),
+        json!({ "get": {
+            "summary": "the metrics of the server, in the Prometheus text format",
+            "responses": { "200": { "description": "the metrics",
+                "content": { "text/plain": { "schema": { "type": "string" } } } } },
+        } }),
+    );
+    paths.insert(
+        "/openapi.json".to_string(),
+        json!({ "get": {
+            "summary": "this document",
+            "responses": { "200": { "description": "the OpenAPI document",
+                "content": { "application/json": { "schema": { "type": "object" } } } } },
+        } }),
+    );
+
+    paths.insert(
+        format!("{}/events", api::V1_PREFIX),
+        json!({ "get": {
+            "summary": "stream the node events as Server-Sent Events, see the `events` module",
+            "parameters": [
+                query_parameter("network", "only the events of this network", json!({ "type": "string" })),
+                query_parameter(
+                    "family",
+                    "only the events of the nodes with an address of this family",
+                    json!({ "type": "string", "enum": ["v4", "v6", "any"], "default": "any" }),
+                ),
+                query_parameter(
+                    "last_event_id",
+                    "resume the stream after this event, unless the `Last-Event-ID` header is set",
+                    integer(),
+                ),
+            ],
+            "responses": {
+                "200": { "description": "the events, `registered`, `deregistered`, `expired`, `removed`, \
+                                         `healthy`, `unhealthy` or `lost`",
+                    "content": { "text/event-stream": { "schema": { "type": "string" } } } },
+                "400": { "description": "`invalid_query`",
+                    "content": { "application/json": { "schema": schemas.error() } } },
+                "404": { "description": "`unknown_network`",
+                    "content": { "application/json": { "schema": schemas.error() } } },
+                "429": { "description": "`rate_limited`, see the `Retry-After` header",
+                    "content": { "application/json": { "schema": schemas.error() } } },
+            },
+        } }),
+    );
+
+    let network = json!({
+        "name": "network",
+        "in": "path",
+        "required": true,
+        "description": "one of the networks served, e.g. `testnet`",
+        "schema": { "type": "string", "pattern": "^[a-z0-9_]+$" },
+    });
+    for operation in node_operations(&mut schemas) {
+        let mut description = json!({
+            "summary": operation.summary,
+            "parameters": operation.parameters,
+            "responses": responses(&operation, &mut schemas),
+        });
+        if let Some(request_body) = &operation.request_body {
+            description["requestBody"] = request_body.clone();
+        }
+        // the same operation for the default network and for any network
+        let mut with_network = description.clone();
+        with_network["parameters"].as_array_mut().unwrap().insert(0, network.clone());
+        for (path, description) in [
+            (format!("{}{}", api::V1_PREFIX, operation.path), description),
+            (format!("{}/{{network}}{}", api::V1_PREFIX, operation.path), with_network),
+        ] {
+            let item = paths.entry(path).or_insert_with(|| json!({}));
+            item[operation.method] = description;
+        }
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "rusty coin DNS",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "the DNS seed of the rusty coin network: nodes register and send heartbeats, \
+                            clients query the active nodes",
+        },
+        "paths": paths,
+        "components": { "schemas": schemas.into_components() },
+    })
+}
+
+/// check that `value` matches `schema`, in the subset of JSON schema used by the document
+#[cfg(test)]
+pub fn validate(document: &Value, schema: &Value, value: &Value) -> Result<(), String> {
+    if let Some(reference) = schema["$ref"].as_str() {
+        let name = reference.trim_start_matches("#/components/schemas/");
+        return validate(document, &document["components"]["schemas"][name], value);
+    }
+    for schema in schema["allOf"].as_array().into_iter().flatten() {
+        validate(document, schema, value)?;
+    }
+    if value.is_null() {
+        return match schema["nullable"].as_bool() {
+            Some(true) => Ok(()),
+            _ => Err("null is not nullable".to_string()),
+        };
+    }
+    let valid = match schema["type"].as_str() {
+        Some("object") => value.is_object(),
+        Some("array") => value.is_array(),
+        Some("string") => value.is_string(),
+        Some("integer") => value.is_u64() || value.is_i64(),
+        Some("boolean") => value.is_boolean(),
+        _ => true,
+    };
+    if !valid {
+        return Err(format!("{} is not a {}", value, schema["type"]));
+    }
+    if let Some(allowed) = schema["enum"].as_array() {
+        if !allowed.contains(value) {
+            return Err(format!("{} is not one of {:?}", value, allowed));
+        }
+    }
+    if let Some(items) = value.as_array() {
+        for item in items {
+            validate(document, &schema["items"], item)?;
+        }
+    }
+    if let Some(object) = value.as_object() {
+        for required in schema["required"].as_array().into_iter().flatten() {
+            if !object.contains_key(required.as_str().unwrap()) {
+                return Err(format!("{} is missing", required));
+            }
+        }
+        for (key, value) in object {
+            match schema["properties"].get(key) {
+                Some(property) => validate(document, property, value).map_err(|err| format!("{}: {}", key, err))?,
+                None if schema["additionalProperties"] == false => return Err(format!("{} is not documented", key)),
+                None => {}
+            }
+        }
+    }
+    Ok(())
+}
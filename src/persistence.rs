@@ -0,0 +1,302 @@
+//! durable storage of the registered nodes, so that the list survives restarts
+//! the storage directory holds two files:
+//! - `nodes.snapshot.json`: all the nodes at the time of the last compaction
+//! - `nodes.log`: an append-only log with one JSON entry per line
+//!   for every registration, deactivation, purge and removal since the snapshot
+//!
+//! on startup the snapshot is loaded, the log is replayed on top of it and compacted.
+//! every entry is synced to disk before the request is answered.
+//! a failed append is cut off the log again, so that the next entries start on a line of their own;
+//! a line that still cannot be read (the server crashed in the middle of a write) is skipped on replay.
+//! compaction writes the new snapshot to a temporary file, syncs it and atomically renames it
+//! over the old one before truncating the log; every entry carries a sequence number,
+//! so the entries that are already part of the snapshot are skipped if the server crashes
+//! between the rename and the truncation
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::node::{Node, NodeAddr};
+use crate::store::upsert;
+
+const SNAPSHOT_FILE: &str = "nodes.snapshot.json";
+const SNAPSHOT_TMP_FILE: &str = "nodes.snapshot.json.tmp";
+const LOG_FILE: &str = "nodes.log";
+
+/// a change to the list of nodes
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    Register { node: Node },
+    /// the node deregistered, or its TTL and grace period lapsed
+    Deactivate {
+        #[serde(flatten)]
+        address: NodeAddr,
+        inactive_since: u64,
+    },
+    /// the node is removed, written when it is purged or removed by an operator
+    Deregister {
+        #[serde(flatten)]
+        address: NodeAddr,
+    },
+}
+
+impl Op {
+    /// apply the change to a list of nodes, the same way the handlers do
+    fn apply(self, nodes: &mut Vec<Node>) {
+        match self {
+            Op::Register { node } => {
+                upsert(nodes, node);
+            }
+            Op::Deactivate { address, inactive_since } => {
+                for node in nodes.iter_mut().filter(|node| address.matches(node)) {
+                    node.inactive_since.get_or_insert(inactive_since);
+                }
+            }
+            Op::Deregister { address } => nodes.retain(|node| !address.matches(node)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LogEntry {
+    seq: u64,
+    #[serde(flatten)]
+    op: Op,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    /// sequence number of the last log entry included in the snapshot
+    seq: u64,
+    nodes: Vec<Node>,
+}
+
+/// the append-only log of the node list
+pub struct NodeLog {
+    dir: PathBuf,
+    log: File,
+    /// sequence number of the last entry written
+    seq: u64,
+    /// number of entries in the log since the last compaction
+    entries: usize,
+    /// a failed append could not be cut off the log, refuse to append until the next compaction
+    poisoned: bool,
+}
+
+impl NodeLog {
+    /// open the storage in `dir` (created if missing), and load the nodes stored in it
+    pub fn open(dir: &Path) -> io::Result<(NodeLog, Vec<Node>)> {
+        fs::create_dir_all(dir)?;
+
+        let (mut seq, mut nodes) = match File::open(dir.join(SNAPSHOT_FILE)) {
+            Ok(file) => {
+                let snapshot: Snapshot = serde_json::from_reader(BufReader::new(file))
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                (snapshot.seq, snapshot.nodes)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (0, Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        match File::open(dir.join(LOG_FILE)) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    let entry: LogEntry = match serde_json::from_str(&line) {
+                        Ok(entry) => entry,
+                        Err(err) => {
+                            // a torn line was never acknowledged, but the entries after it were
+                            tracing::warn!(error = %err, "skipping an unreadable line of the node log");
+                            continue;
+                        }
+                    };
+                    if entry.seq > seq {
+                        seq = entry.seq;
+                        entry.op.apply(&mut nodes);
+                    }
+                }
+            }
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            Err(_) => {}
+        }
+
+        let log = OpenOptions::new().create(true).append(true).open(dir.join(LOG_FILE))?;
+        let mut node_log = NodeLog {
+            dir: dir.to_path_buf(),
+            log,
+            seq,
+            entries: 0,
+            poisoned: false,
+        };
+        // start with an empty log, this also drops a torn last line
+        node_log.compact(&nodes)?;
+
+        Ok((node_log, nodes))
+    }
+
+    /// durably append a change to the log
+    /// if the write fails, the log is truncated back to its previous length, so that no torn bytes
+    /// are left in front of the next entries
+    pub fn append(&mut self, op: Op) -> io::Result<()> {
+        if self.poisoned {
+            return Err(io::Error::other("the node log holds a torn entry, it must be compacted first"));
+        }
+        let entry = LogEntry {
+            seq: self.seq + 1,
+            op,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        let len = self.log.metadata()?.len();
+        if let Err(err) = self.log.write_all(&line).and_then(|()| self.log.sync_data()) {
+            if self.log.set_len(len).and_then(|()| self.log.sync_data()).is_err() {
+                self.poisoned = true;
+            }
+            return Err(err);
+        }
+
+        self.seq = entry.seq;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// number of entries in the log since the last compaction
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// replace the snapshot with `nodes` (the result of the whole log) and truncate the log
+    pub fn compact(&mut self, nodes: &[Node]) -> io::Result<()> {
+        let tmp_path = self.dir.join(SNAPSHOT_TMP_FILE);
+        let mut tmp = File::create(&tmp_path)?;
+        serde_json::to_writer(&mut tmp, &SnapshotRef { seq: self.seq, nodes })?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+        // make the rename itself durable
+        File::open(&self.dir)?.sync_all()?;
+
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+        self.entries = 0;
+        self.poisoned = false;
+        Ok(())
+    }
+}
+
+/// a snapshot borrowing the nodes, to write it without cloning them
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    seq: u64,
+    nodes: &'a [Node],
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::test_node;
+
+    /// an empty storage directory for the test `name`
+    fn storage_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rusty_coin_dns_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// the addresses and the deactivation times of `nodes`
+    fn summary(nodes: &[Node]) -> Vec<(String, Option<u64>)> {
+        nodes.iter().map(|node| (node.addr().to_string(), node.inactive_since)).collect()
+    }
+
+    #[test]
+    fn nodes_are_loaded_again_after_a_restart() {
+        let dir = storage_dir("restart");
+        let (mut log, nodes) = NodeLog::open(&dir).unwrap();
+        assert!(nodes.is_empty());
+        for port in [1, 2, 3] {
+            log.append(Op::Register { node: test_node("93.184.216.34", port, 0) }).unwrap();
+        }
+        log.append(Op::Deactivate { address: test_node("93.184.216.34", 2, 0).addr(), inactive_since: 100 }).unwrap();
+        log.append(Op::Deregister { address: test_node("93.184.216.34", 3, 0).addr() }).unwrap();
+        drop(log);
+
+        let (log, nodes) = NodeLog::open(&dir).unwrap();
+        assert_eq!(
+            summary(&nodes),
+            [("93.184.216.34:1".to_string(), None), ("93.184.216.34:2".to_string(), Some(100))]
+        );
+        // the log was folded into the snapshot on startup
+        assert_eq!(log.entries(), 0);
+        assert_eq!(fs::metadata(dir.join(LOG_FILE)).unwrap().len(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_torn_last_line_is_ignored() {
+        let dir = storage_dir("torn");
+        let (mut log, _) = NodeLog::open(&dir).unwrap();
+        log.append(Op::Register { node: test_node("93.184.216.34", 1, 0) }).unwrap();
+        log.append(Op::Register { node: test_node("93.184.216.34", 2, 0) }).unwrap();
+        drop(log);
+        // the server crashed in the middle of the second entry
+        let len = fs::metadata(dir.join(LOG_FILE)).unwrap().len();
+        OpenOptions::new().write(true).open(dir.join(LOG_FILE)).unwrap().set_len(len - 10).unwrap();
+
+        let (mut log, nodes) = NodeLog::open(&dir).unwrap();
+        assert_eq!(summary(&nodes), [("93.184.216.34:1".to_string(), None)]);
+        // the torn line is dropped, the next entries are read again
+        log.append(Op::Register { node: test_node("93.184.216.34", 3, 0) }).unwrap();
+        drop(log);
+        let (_, nodes) = NodeLog::open(&dir).unwrap();
+        assert_eq!(nodes.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn entries_after_an_unreadable_line_are_kept() {
+        let dir = storage_dir("garbage");
+        let (mut log, _) = NodeLog::open(&dir).unwrap();
+        log.append(Op::Register { node: test_node("93.184.216.34", 1, 0) }).unwrap();
+        // the bytes of an append that failed halfway, then an acknowledged entry
+        log.log.write_all(b"{\"seq\":2,\"op\":\"regis\n").unwrap();
+        log.append(Op::Register { node: test_node("93.184.216.34", 2, 0) }).unwrap();
+        drop(log);
+
+        let (_, nodes) = NodeLog::open(&dir).unwrap();
+        assert_eq!(
+            summary(&nodes),
+            [("93.184.216.34:1".to_string(), None), ("93.184.216.34:2".to_string(), None)]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_skips_the_entries_already_in_the_snapshot() {
+        let dir = storage_dir("compaction");
+        let (mut log, _) = NodeLog::open(&dir).unwrap();
+        log.append(Op::Register { node: test_node("93.184.216.34", 1, 0) }).unwrap();
+        log.append(Op::Deactivate { address: test_node("93.184.216.34", 1, 0).addr(), inactive_since: 100 }).unwrap();
+        let entries = fs::read(dir.join(LOG_FILE)).unwrap();
+        let mut nodes = vec![test_node("93.184.216.34", 1, 0)];
+        nodes[0].inactive_since = Some(100);
+        log.compact(&nodes).unwrap();
+        assert_eq!(log.entries(), 0);
+        assert_eq!(fs::metadata(dir.join(LOG_FILE)).unwrap().len(), 0);
+        drop(log);
+
+        // the server crashed after the new snapshot was renamed, but before the log was truncated:
+        // replaying the registration again would make the node active again
+        fs::write(dir.join(LOG_FILE), &entries).unwrap();
+        let (mut log, loaded) = NodeLog::open(&dir).unwrap();
+        assert_eq!(summary(&loaded), [("93.184.216.34:1".to_string(), Some(100))]);
+        assert!(!dir.join(SNAPSHOT_TMP_FILE).exists());
+
+        // the entries after the snapshot are still replayed
+        log.append(Op::Register { node: test_node("93.184.216.34", 1, 0) }).unwrap();
+        drop(log);
+        let (_, loaded) = NodeLog::open(&dir).unwrap();
+        assert_eq!(summary(&loaded), [("93.184.216.34:1".to_string(), None)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+//! proof-of-work gated registration, so that a single host cannot cheaply flood the DNS server
+//! with fake nodes
+//! when enabled, a new node must solve a hashcash-style challenge before it is registered:
+//! 1. `GET /register/challenge` returns a random `challenge` and a `difficulty` in bits
+//! 2. the node looks for a `solution` (any string of 1 to 64 characters, e.g. a counter)
+//!    such that `SHA-256("<challenge>:<solution>")` starts with `difficulty` zero bits
+//! 3. `POST /register` carries `pow_challenge` and `pow_solution` next to the address fields
+//!
+//! a challenge can only be used once and expires after a while,
+//! refreshing a node that is already registered does not require a proof of work
+//!
+//! the difficulty of a new challenge grows with the registration rate: every time the number of
+//! new nodes in the last minute doubles past the target rate, one bit is added
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use rand::RngCore;
+use schemars::JsonSchema;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::api::ApiError;
+use crate::config::Config;
+use crate::validation::NodeRequest;
+
+/// maximum length of a solution
+const MAX_SOLUTION_LEN: usize = 64;
+/// maximum number of challenges waiting for a solution,
+/// so that requesting challenges cannot exhaust the memory of the server
+const MAX_PENDING_CHALLENGES: usize = 100_000;
+/// the window (in seconds) over which the registration rate is measured
+const RATE_WINDOW_SECS: u64 = 60;
+
+/// a challenge handed out by `GET /register/challenge`
+#[derive(Debug, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct Challenge {
+    pub challenge: String,
+    /// the number of leading zero bits the hash of the solution must have
+    pub difficulty: u32,
+    /// how many seconds the challenge can be solved in
+    pub expires_in: u64,
+}
+
+/// issues the challenges and checks their solutions
+pub struct PowGuard {
+    /// the difficulty when the registration rate is at most the target rate
+    base_difficulty: u32,
+    max_difficulty: u32,
+    /// the number of new nodes per minute above which the difficulty grows
+    target_rate: usize,
+    challenge_ttl_secs: u64,
+    /// the pending challenges, with their difficulty and the time they expire
+    challenges: Mutex<HashMap<String, (u32, u64)>>,
+    /// the times of the registrations of new nodes within the last minute
+    registrations: Mutex<VecDeque<u64>>,
+}
+
+impl PowGuard {
+    pub fn new(config: &Config) -> PowGuard {
+        PowGuard {
+            base_difficulty: config.pow_difficulty,
+            max_difficulty: config.pow_max_difficulty.max(config.pow_difficulty),
+            target_rate: config.pow_target_rate.max(1),
+            challenge_ttl_secs: config.pow_challenge_ttl_secs,
+            challenges: Mutex::new(HashMap::new()),
+            registrations: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// the difficulty of a challenge issued at `now`, given the current registration rate
+    pub fn difficulty(&self, now: u64) -> u32 {
+        let rate = self.recent_registrations(now);
+        let mut extra = 0;
+        while self.target_rate.checked_shl(extra).is_some_and(|limit| limit < rate) {
+            extra += 1;
+        }
+        (self.base_difficulty + extra).min(self.max_difficulty)
+    }
+
+    /// issue a new challenge, `None` if too many challenges are waiting for a solution
+    pub fn issue(&self, now: u64) -> Option<Challenge> {
+        let difficulty = self.difficulty(now);
+        let mut bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let challenge = hex::encode(bytes);
+
+        let mut challenges = self.challenges.lock().unwrap();
+        if challenges.len() >= MAX_PENDING_CHALLENGES {
+            challenges.retain(|_, (_, expires_at)| *expires_at > now);
+            if challenges.len() >= MAX_PENDING_CHALLENGES {
+                return None;
+            }
+        }
+        challenges.insert(challenge.clone(), (difficulty, now + self.challenge_ttl_secs));
+        Some(Challenge {
+            challenge,
+            difficulty,
+            expires_in: self.challenge_ttl_secs,
+        })
+    }
+
+    /// check the proof of work carried by a registration, the challenge is used up if it is valid
+    pub fn verify(&self, req: &NodeRequest, now: u64) -> Result<(), ApiError> {
+        let (challenge, solution) = match (req.pow_challenge.as_deref(), req.pow_solution.as_deref()) {
+            (Some(challenge), Some(solution)) => (challenge.trim(), solution),
+            _ => {
+                return Err(ApiError::InvalidProofOfWork(
+                    "a new node must solve a challenge of GET /register/challenge, \
+                     and post pow_challenge and pow_solution"
+                        .to_string(),
+                ))
+            }
+        };
+        if solution.is_empty() || solution.len() > MAX_SOLUTION_LEN {
+            return Err(ApiError::InvalidProofOfWork(format!(
+                "pow_solution must be 1 to {} characters",
+                MAX_SOLUTION_LEN
+            )));
+        }
+
+        let mut challenges = self.challenges.lock().unwrap();
+        let difficulty = match challenges.get(challenge) {
+            Some((difficulty, expires_at)) if *expires_at > now => *difficulty,
+            _ => return Err(ApiError::InvalidProofOfWork("the challenge is unknown, used or expired".to_string())),
+        };
+        if leading_zero_bits(&hash(challenge, solution)) < difficulty {
+            return Err(ApiError::InvalidProofOfWork(format!(
+                "the solution does not meet the difficulty of {} bits",
+                difficulty
+            )));
+        }
+        challenges.remove(challenge);
+        Ok(())
+    }
+
+    /// count the registration of a new node at `now` towards the registration rate
+    pub fn record_registration(&self, now: u64) {
+        let mut registrations = self.registrations.lock().unwrap();
+        registrations.push_back(now);
+        prune(&mut registrations, now);
+    }
+
+    /// the number of new nodes registered within the last minute
+    fn recent_registrations(&self, now: u64) -> usize {
+        let mut registrations = self.registrations.lock().unwrap();
+        prune(&mut registrations, now);
+        registrations.len()
+    }
+}
+
+/// forget the registrations older than the rate window
+fn prune(registrations: &mut VecDeque<u64>, now: u64) {
+    while registrations
+        .front()
+        .is_some_and(|time| now.saturating_sub(*time) >= RATE_WINDOW_SECS)
+    {
+        registrations.pop_front();
+    }
+}
+
+/// `SHA-256("<challenge>:<solution>")`
+pub fn hash(challenge: &str, solution: &str) -> [u8; 32] {
+    Sha256::digest(format!("{}:{}", challenge, solution)).into()
+}
+
+/// the number of leading zero bits of `bytes`
+pub fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn difficulty_grows_with_the_registration_rate() {
+        let guard = PowGuard::new(&Config {
+            pow_difficulty: 10,
+            pow_max_difficulty: 12,
+            pow_target_rate: 4,
+            ..Config::default()
+        });
+        let mut difficulties = Vec::new();
+        for _ in 0..20 {
+            guard.record_registration(1000);
+            difficulties.push(guard.difficulty(1000));
+        }
+        // 4 registrations per minute are free, then one bit per doubling, capped at 12
+        assert_eq!(difficulties[3], 10);
+        assert_eq!(difficulties[4], 11);
+        assert_eq!(difficulties[7], 11);
+        assert_eq!(difficulties[8], 12);
+        assert_eq!(difficulties[19], 12);
+        // the rate is measured over the last minute only
+        assert_eq!(guard.difficulty(1000 + RATE_WINDOW_SECS), 10);
+    }
+}
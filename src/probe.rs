@@ -0,0 +1,150 @@
+//! active health probing of the registered nodes
+//! the prober periodically opens a TCP connection to every node (with a timeout)
+//! and records the outcome on the node, only nodes that passed their recent probes
+//! are handed out by `GET /query` and the DNS listener
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use actix_web::rt::net::TcpStream;
+use futures_util::stream::{self, StreamExt};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// the probe results of a node
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct Health {
+    /// number of probes the node passed
+    pub successes: u64,
+    /// number of probes the node failed
+    pub failures: u64,
+    /// number of probes failed in a row since the last success
+    pub consecutive_failures: u32,
+    /// TCP connect latency of the last successful probe, in milliseconds
+    pub latency_ms: Option<u64>,
+    /// the last time (UNIX timestamp in seconds) the node was probed
+    pub last_probe: Option<u64>,
+    /// the last time (UNIX timestamp in seconds) the node passed a probe
+    pub last_success: Option<u64>,
+}
+
+impl Health {
+    /// record the outcome of a probe, `latency` is `None` if the probe failed
+    pub fn record(&mut self, latency: Option<Duration>, now: u64) {
+        self.last_probe = Some(now);
+        match latency {
+            Some(latency) => {
+                self.successes += 1;
+                self.consecutive_failures = 0;
+                self.latency_ms = Some(latency.as_millis() as u64);
+                self.last_success = Some(now);
+            }
+            None => {
+                self.failures += 1;
+                self.consecutive_failures += 1;
+            }
+        }
+    }
+
+    /// whether the node passed a probe and has not failed `failure_threshold` probes in a row since
+    pub fn is_healthy(&self, failure_threshold: u32) -> bool {
+        self.last_success.is_some() && self.consecutive_failures < failure_threshold
+    }
+}
+
+/// open a TCP connection to `address`, return the connect latency
+/// or `None` if the connection failed or timed out
+pub async fn probe(address: SocketAddr, timeout: Duration) -> Option<Duration> {
+    let start = Instant::now();
+    match actix_web::rt::time::timeout(timeout, TcpStream::connect(address)).await {
+        Ok(Ok(_)) => Some(start.elapsed()),
+        _ => None,
+    }
+}
+
+/// probe all `addresses` with at most `concurrency` probes in flight
+pub async fn probe_all(
+    addresses: Vec<SocketAddr>,
+    timeout: Duration,
+    concurrency: usize,
+) -> Vec<(SocketAddr, Option<Duration>)> {
+    stream::iter(addresses)
+        .map(|address| async move { (address, probe(address, timeout).await) })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// a local port nothing listens on
+    fn closed_port() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    /// a local listener that never accepts, with its backlog full,
+    /// so that new connections hang until they time out
+    async fn unresponsive_listener() -> (actix_web::rt::net::TcpListener, Vec<TcpStream>) {
+        let socket = actix_web::rt::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut filling = Vec::new();
+        while let Ok(Ok(stream)) =
+            actix_web::rt::time::timeout(Duration::from_millis(100), TcpStream::connect(address)).await
+        {
+            filling.push(stream);
+        }
+        (listener, filling)
+    }
+
+    #[actix_web::test]
+    async fn probes_tell_open_ports_from_closed_ones() {
+        let open = TcpListener::bind("127.0.0.1:0").unwrap();
+        let timeout = Duration::from_secs(1);
+        assert!(probe(open.local_addr().unwrap(), timeout).await.is_some());
+        assert!(probe(closed_port(), timeout).await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn probes_time_out_within_the_concurrency_limit() {
+        let (listener, _filling) = unresponsive_listener().await;
+        let address = listener.local_addr().unwrap();
+        let timeout = Duration::from_millis(300);
+
+        let start = Instant::now();
+        assert!(probe(address, timeout).await.is_none());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= timeout && elapsed < timeout * 3, "{:?}", elapsed);
+
+        // 4 probes hanging until their timeout take 4 timeouts one at a time, 1 all at once
+        for (concurrency, min, max) in [(1, timeout * 4, timeout * 7), (4, timeout, timeout * 3)] {
+            let start = Instant::now();
+            let results = probe_all(vec![address; 4], timeout, concurrency).await;
+            let elapsed = start.elapsed();
+            assert!(results.iter().all(|(_, latency)| latency.is_none()));
+            assert!(elapsed >= min && elapsed < max, "concurrency {}: {:?}", concurrency, elapsed);
+        }
+    }
+
+    #[test]
+    fn failures_in_a_row_make_a_node_unhealthy() {
+        let mut health = Health::default();
+        // a node that was never reached is not healthy
+        assert!(!health.is_healthy(2));
+        health.record(Some(Duration::from_millis(5)), 100);
+        assert!(health.is_healthy(2));
+        health.record(None, 110);
+        assert!(health.is_healthy(2));
+        health.record(None, 120);
+        assert!(!health.is_healthy(2));
+        assert_eq!((health.successes, health.failures, health.consecutive_failures), (1, 2, 2));
+        assert_eq!((health.latency_ms, health.last_probe, health.last_success), (Some(5), Some(120), Some(100)));
+        // a success resets the count
+        health.record(Some(Duration::from_millis(5)), 130);
+        assert!(health.is_healthy(2));
+    }
+}
@@ -0,0 +1,250 @@
+//! rate limiting of the node endpoints, per client
+//! every client gets a token bucket per endpoint: a request takes a token, and the bucket
+//! refills at the configured rate per minute, up to one minute's worth of tokens
+//! - the clients are aggregated by subnet, so that a host cannot dodge the limit by cycling
+//!   through its addresses (by default an IPv6 /64, the usual allocation of a single site,
+//!   and every IPv4 address on its own)
+//! - `/register` (and its challenge), `/heartbeat`, `/deregister` and `/query` (with the listing
+//!   of the inactive nodes and the subscriptions to the node events) have separate budgets,
+//!   a rate of 0 does not limit the endpoint
+//! - the budgets are shared by all the networks and by both versions of the API
+//! - the clients in the allowlist (e.g. trusted infrastructure) are never limited
+//! - a limited request is answered with 429 Too Many Requests, a `Retry-After` header
+//!   and `{"error": "rate_limited", "message": "..."}`
+
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready};
+use actix_web::ResponseError;
+use futures_util::future::LocalBoxFuture;
+use crate::api::{self, ApiError};
+use crate::cidr::Cidr;
+use crate::client;
+use crate::config::Config;
+
+/// the most buckets kept: once reached, the buckets that are full again are forgotten, then
+/// the least recently used ones, down to three quarters of it so that the sweep stays rare
+const MAX_TRACKED_BUCKETS: usize = 100_000;
+
+/// the rate-limited endpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Register,
+    Heartbeat,
+    Deregister,
+    Query,
+}
+
+impl Endpoint {
+    /// the endpoint at `path`, with or without a version and a network prefix (see the `api` and
+    /// `network` modules), `None` if it is not rate-limited
+    fn of(path: &str) -> Option<Endpoint> {
+        let path = path
+            .strip_prefix(api::V1_PREFIX)
+            .filter(|route| route.starts_with('/'))
+            .unwrap_or(path);
+        Endpoint::of_route(path).or_else(|| {
+            let network_len = path.strip_prefix('/')?.find('/')? + 1;
+            Endpoint::of_route(&path[network_len..])
+        })
+    }
+
+    /// the endpoint at `path` without its network prefix
+    fn of_route(path: &str) -> Option<Endpoint> {
+        match path {
+            "/register" | "/register/challenge" => Some(Endpoint::Register),
+            "/heartbeat" => Some(Endpoint::Heartbeat),
+            "/deregister" => Some(Endpoint::Deregister),
+            "/query" | "/nodes/inactive" | "/events" => Some(Endpoint::Query),
+            _ => None,
+        }
+    }
+}
+
+/// a token bucket
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// the token buckets of all the clients
+pub struct RateLimiter {
+    enabled: bool,
+    /// the budget of every endpoint, in requests per minute
+    budgets: HashMap<Endpoint, u32>,
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
+    allowlist: Vec<Cidr>,
+    /// the buckets per endpoint and client subnet
+    buckets: Mutex<HashMap<(Endpoint, Cidr), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> RateLimiter {
+        RateLimiter {
+            enabled: config.rate_limit_enabled,
+            budgets: HashMap::from([
+                (Endpoint::Register, config.rate_limit_register_per_min),
+                (Endpoint::Heartbeat, config.rate_limit_heartbeat_per_min),
+                (Endpoint::Deregister, config.rate_limit_deregister_per_min),
+                (Endpoint::Query, config.rate_limit_query_per_min),
+            ]),
+            ipv4_prefix_len: config.rate_limit_ipv4_prefix_len,
+            ipv6_prefix_len: config.rate_limit_ipv6_prefix_len,
+            allowlist: config.rate_limit_allowlist.clone(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// take a token for a request of `client` to `endpoint` at `now`,
+    /// return how long to wait for the next token if the bucket is empty
+    fn check(&self, endpoint: Endpoint, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let per_min = self.budgets.get(&endpoint).copied().unwrap_or(0);
+        if !self.enabled || per_min == 0 || self.allowlist.iter().any(|range| range.contains(client)) {
+            return Ok(());
+        }
+        let capacity = per_min as f64;
+        let per_sec = capacity / 60.0;
+        let prefix_len = match client.to_canonical() {
+            IpAddr::V4(_) => self.ipv4_prefix_len,
+            IpAddr::V6(_) => self.ipv6_prefix_len,
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_BUCKETS {
+            self.sweep(&mut buckets, now);
+        }
+        let bucket = buckets
+            .entry((endpoint, Cidr::new(client, prefix_len)))
+            .or_insert(Bucket { tokens: capacity, updated: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+
+    /// forget the buckets that are full again at `now`, then the least recently used ones
+    /// until three quarters of `MAX_TRACKED_BUCKETS` are left
+    fn sweep(&self, buckets: &mut HashMap<(Endpoint, Cidr), Bucket>, now: Instant) {
+        // a full bucket is the same as no bucket
+        buckets.retain(|(endpoint, _), bucket| {
+            let capacity = self.budgets.get(endpoint).copied().unwrap_or(0) as f64;
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * capacity / 60.0 < capacity
+        });
+        let excess = buckets.len().saturating_sub(MAX_TRACKED_BUCKETS / 4 * 3);
+        if excess == 0 {
+            return;
+        }
+        let mut by_age: Vec<(Instant, (Endpoint, Cidr))> =
+            buckets.iter().map(|(key, bucket)| (bucket.updated, *key)).collect();
+        by_age.select_nth_unstable_by_key(excess - 1, |(updated, _)| *updated);
+        for (_, key) in &by_age[..excess] {
+            buckets.remove(key);
+        }
+    }
+}
+
+/// the middleware answering the requests over budget with 429 Too Many Requests
+pub struct RateLimit {
+    limiter: Arc<RateLimiter>,
+    trusted_proxies: Vec<Cidr>,
+}
+
+impl RateLimit {
+    /// `trusted_proxies` tell the address a request came from, see the `client` module
+    pub fn new(limiter: Arc<RateLimiter>, trusted_proxies: Vec<Cidr>) -> RateLimit {
+        RateLimit { limiter, trusted_proxies }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service,
+            limiter: self.limiter.clone(),
+            trusted_proxies: self.trusted_proxies.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    limiter: Arc<RateLimiter>,
+    trusted_proxies: Vec<Cidr>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let endpoint = Endpoint::of(req.path());
+        let client = client::client_ip(req.request(), &self.trusted_proxies);
+        if let (Some(endpoint), Some(client)) = (endpoint, client) {
+            if let Err(retry_after) = self.limiter.check(endpoint, client, Instant::now()) {
+                let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+                let response = ApiError::RateLimited(retry_after).error_response();
+                return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+            }
+        }
+
+        let response = self.service.call(req);
+        Box::pin(async move { response.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_least_recently_used_buckets_are_evicted_over_the_cap() {
+        let limiter = RateLimiter::new(&Config {
+            rate_limit_enabled: true,
+            ..Config::default()
+        });
+        let start = Instant::now();
+        // every client takes a token, so that none of the buckets is full again
+        let client = |index: usize| IpAddr::from((index as u32 + 1).to_be_bytes());
+        for index in 0..MAX_TRACKED_BUCKETS {
+            let now = start + Duration::from_micros(index as u64);
+            assert!(limiter.check(Endpoint::Query, client(index), now).is_ok());
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), MAX_TRACKED_BUCKETS);
+
+        let now = start + Duration::from_micros(MAX_TRACKED_BUCKETS as u64);
+        assert!(limiter.check(Endpoint::Query, client(MAX_TRACKED_BUCKETS), now).is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_TRACKED_BUCKETS / 4 * 3 + 1);
+        let tracked = |index: usize| buckets.contains_key(&(Endpoint::Query, Cidr::new(client(index), 32)));
+        assert!(!tracked(0) && !tracked(MAX_TRACKED_BUCKETS / 4 - 1));
+        assert!(tracked(MAX_TRACKED_BUCKETS / 4) && tracked(MAX_TRACKED_BUCKETS));
+    }
+}
@@ -0,0 +1,176 @@
+//! signed registrations with Ed25519 node keys
+//! a node may bind its registration to its public key by signing the request,
+//! from then on every heartbeat, deregistration and re-registration of the node
+//! must be signed with the same key
+//!
+//! a signed request carries, next to the address fields:
+//! - `public_key`: the Ed25519 public key of the node, 32 bytes hex-encoded
+//! - `timestamp`: the current UNIX timestamp in seconds
+//! - `nonce`: a random string of 1 to 64 characters, never reused by the same key
+//! - `signature`: the Ed25519 signature of the canonical message, 64 bytes hex-encoded
+//!
+//! the canonical message is the following lines joined by `\n`, where the address fields
+//! are exactly the strings posted in the body (empty if omitted):
+//! `rusty_coin_dns`, the action (`register`, `heartbeat` or `deregister`), `ipv4_address`,
+//! `ipv6_address`, `port`, `public_key`, `timestamp`, `nonce`
+//!
+//! a request whose timestamp is too far from the server time, or whose nonce was already
+//! seen for the same key within that window, is rejected as a replay
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use ed25519_dalek::{Signature, VerifyingKey};
+use crate::api::ApiError;
+use crate::node::Node;
+use crate::validation::NodeRequest;
+
+/// maximum length of a nonce
+const MAX_NONCE_LEN: usize = 64;
+
+/// the actions a node can sign
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    Register,
+    Heartbeat,
+    Deregister,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::Register => "register",
+            Action::Heartbeat => "heartbeat",
+            Action::Deregister => "deregister",
+        }
+    }
+}
+
+/// the canonical message signed by a node
+pub fn canonical_message(action: Action, req: &NodeRequest) -> String {
+    [
+        "rusty_coin_dns",
+        action.as_str(),
+        req.ipv4_address.as_deref().unwrap_or(""),
+        req.ipv6_address.as_deref().unwrap_or(""),
+        &req.port.to_string(),
+        req.public_key.as_deref().unwrap_or(""),
+        &req.timestamp.map(|timestamp| timestamp.to_string()).unwrap_or_default(),
+        req.nonce.as_deref().unwrap_or(""),
+    ]
+    .join("\n")
+}
+
+/// remembers the nonces seen recently, to reject replayed requests
+pub struct ReplayGuard {
+    /// how far (in seconds) a timestamp may be from the server time
+    max_skew_secs: u64,
+    /// the nonces seen per public key, with the time they can be forgotten
+    seen: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl ReplayGuard {
+    pub fn new(max_skew_secs: u64) -> ReplayGuard {
+        ReplayGuard {
+            max_skew_secs,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// accept the timestamp and the nonce of a request signed by `public_key`, only once
+    fn check(&self, public_key: &str, timestamp: u64, nonce: &str, now: u64) -> Result<(), ApiError> {
+        if timestamp.abs_diff(now) > self.max_skew_secs {
+            return Err(ApiError::InvalidSignature(format!(
+                "timestamp {} is more than {} seconds away from the server time {}",
+                timestamp, self.max_skew_secs, now
+            )));
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        // a nonce is only needed until its timestamp falls out of the window
+        seen.retain(|_, forget_at| *forget_at > now);
+        let key = (public_key.to_string(), nonce.to_string());
+        if seen.contains_key(&key) {
+            return Err(ApiError::InvalidSignature("the nonce was already used".to_string()));
+        }
+        seen.insert(key, timestamp + self.max_skew_secs + 1);
+        Ok(())
+    }
+}
+
+/// check the signature of a request about the nodes `existing` registered at its address
+/// return the public key the nodes are bound to after the request, if any
+/// - a signed request must carry a valid signature, a fresh timestamp and an unused nonce
+/// - a request about nodes bound to a key must be signed with that key, since the request
+///   acts on every matching node (a dual-stack address can match one node per address family)
+/// - an unsigned request is rejected if `require_signatures` is set
+pub fn authorize(
+    action: Action,
+    req: &NodeRequest,
+    existing: &[Node],
+    require_signatures: bool,
+    guard: &ReplayGuard,
+    now: u64,
+) -> Result<Option<String>, ApiError> {
+    let mut bound_keys = existing.iter().filter_map(|node| node.public_key.as_deref());
+    let bound_key = bound_keys.next();
+    if bound_keys.any(|other| Some(other) != bound_key) {
+        return Err(ApiError::KeyMismatch(
+            "the address matches nodes bound to different public keys".to_string(),
+        ));
+    }
+
+    let public_key = match req.public_key.as_deref() {
+        Some(public_key) => public_key.trim().to_ascii_lowercase(),
+        None if bound_key.is_some() => {
+            return Err(ApiError::KeyMismatch(
+                "the node is bound to a public key, the request must be signed with it".to_string(),
+            ))
+        }
+        None if require_signatures => {
+            return Err(ApiError::InvalidSignature("the request must be signed".to_string()))
+        }
+        None => return Ok(None),
+    };
+    if bound_key.is_some_and(|bound_key| bound_key != public_key) {
+        return Err(ApiError::KeyMismatch(
+            "the node is bound to a different public key".to_string(),
+        ));
+    }
+
+    let key_bytes: [u8; 32] = decode_hex(&public_key, "public_key")?;
+    let verifying_key = VerifyingKey::from_bytes(&key_bytes)
+        .map_err(|_| ApiError::InvalidSignature("public_key is not a valid Ed25519 key".to_string()))?;
+    let signature_bytes: [u8; 64] = match req.signature.as_deref() {
+        Some(signature) => decode_hex(signature.trim(), "signature")?,
+        None => return Err(ApiError::InvalidSignature("signature is missing".to_string())),
+    };
+    let (timestamp, nonce) = match (req.timestamp, req.nonce.as_deref()) {
+        (Some(timestamp), Some(nonce)) if !nonce.is_empty() && nonce.len() <= MAX_NONCE_LEN => {
+            (timestamp, nonce)
+        }
+        _ => {
+            return Err(ApiError::InvalidSignature(format!(
+                "a signed request requires a timestamp and a nonce of 1 to {} characters",
+                MAX_NONCE_LEN
+            )))
+        }
+    };
+
+    let message = canonical_message(action, req);
+    verifying_key
+        .verify_strict(message.as_bytes(), &Signature::from_bytes(&signature_bytes))
+        .map_err(|_| ApiError::InvalidSignature("the signature does not match".to_string()))?;
+    // only remember the nonce of a genuine request, so that forged requests cannot burn nonces
+    guard.check(&public_key, timestamp, nonce, now)?;
+
+    Ok(Some(public_key))
+}
+
+/// decode a hex-encoded field of exactly `N` bytes
+fn decode_hex<const N: usize>(value: &str, field: &str) -> Result<[u8; N], ApiError> {
+    let mut bytes = [0u8; N];
+    hex::decode_to_slice(value, &mut bytes).map_err(|_| {
+        ApiError::InvalidSignature(format!("{} must be {} bytes hex-encoded", field, N))
+    })?;
+    Ok(bytes)
+}
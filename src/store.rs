@@ -0,0 +1,368 @@
+//! storage of the registered nodes
+//! the handlers only talk to a `NodeStore`, injected as `web::Data<dyn NodeStore>`,
+//! so the backend can be swapped (or faked in tests) without touching the HTTP code
+//! - `MemoryStore`: keeps the nodes in memory only
+//! - `FileStore`: keeps the nodes in memory and logs every change to disk,
+//!   see the `persistence` module
+
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use rand::prelude::SliceRandom;
+use crate::node::{Node, NodeAddr};
+use crate::persistence::{NodeLog, Op};
+
+pub trait NodeStore: Send + Sync {
+    /// add a node, or refresh the node already registered at the same port and either address,
+    /// return `true` if the node was added (or was inactive)
+    /// `accept` is given all the stored nodes under the same lock as the insertion,
+    /// so that no other change slips in between, return `None` if it rejected the node
+    fn insert_if(&self, node: Node, accept: &mut dyn FnMut(&[Node]) -> bool) -> io::Result<Option<bool>>;
+
+    /// mark the nodes registered at the port and either address of `address` inactive at `now`,
+    /// return the nodes that were not inactive yet
+    fn deactivate(&self, address: &NodeAddr, now: u64) -> io::Result<Vec<Node>>;
+
+    /// the nodes registered at the port and either address of `address`,
+    /// a dual-stack address can match several nodes, one per address family
+    fn find(&self, address: &NodeAddr) -> Vec<Node>;
+
+    /// apply `update` to the nodes registered at `address`,
+    /// return whether any node is registered there
+    /// the update is not persisted, it is meant for runtime state like heartbeats and probes
+    fn update(&self, address: &NodeAddr, update: &mut dyn FnMut(&mut Node)) -> bool;
+
+    /// apply `edit` to the node registered at `address` and persist the change,
+    /// return the edited node, `None` if no node is registered there
+    fn edit(&self, address: &NodeAddr, edit: &mut dyn FnMut(&mut Node)) -> io::Result<Option<Node>>;
+
+    /// randomly pick up to `count` distinct nodes among those accepted by `filter`
+    fn sample(&self, count: usize, filter: &dyn Fn(&Node) -> bool) -> Vec<Node>;
+
+    /// all the nodes
+    fn list(&self) -> Vec<Node>;
+
+    /// mark the nodes that have not sent a heartbeat within the last `max_age` seconds inactive,
+    /// return the nodes that were not inactive yet
+    fn expire(&self, now: u64, max_age: u64) -> io::Result<Vec<Node>>;
+
+    /// remove the nodes that have been inactive for `retention` seconds,
+    /// return the removed nodes
+    fn purge(&self, now: u64, retention: u64) -> io::Result<Vec<Node>>;
+
+    /// remove the nodes accepted by `filter` right away, whatever their state,
+    /// return the removed nodes
+    fn remove(&self, filter: &dyn Fn(&Node) -> bool) -> io::Result<Vec<Node>>;
+
+    /// fold the pending changes into a compact form, if the backend has any
+    fn compact(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// a store keeping the nodes in memory only
+#[derive(Default)]
+pub struct MemoryStore {
+    nodes: Mutex<Vec<Node>>,
+}
+
+impl MemoryStore {
+    pub fn new(nodes: Vec<Node>) -> MemoryStore {
+        MemoryStore {
+            nodes: Mutex::new(nodes),
+        }
+    }
+}
+
+impl NodeStore for MemoryStore {
+    fn insert_if(&self, node: Node, accept: &mut dyn FnMut(&[Node]) -> bool) -> io::Result<Option<bool>> {
+        let mut nodes = self.nodes.lock().unwrap();
+        if !accept(&nodes) {
+            return Ok(None);
+        }
+        Ok(Some(upsert(&mut nodes, node)))
+    }
+
+    fn deactivate(&self, address: &NodeAddr, now: u64) -> io::Result<Vec<Node>> {
+        let mut nodes = self.nodes.lock().unwrap();
+        Ok(deactivate_where(&mut nodes, now, |node| address.matches(node)))
+    }
+
+    fn find(&self, address: &NodeAddr) -> Vec<Node> {
+        let nodes = self.nodes.lock().unwrap();
+        nodes.iter().filter(|node| address.matches(node)).cloned().collect()
+    }
+
+    fn update(&self, address: &NodeAddr, update: &mut dyn FnMut(&mut Node)) -> bool {
+        let mut nodes = self.nodes.lock().unwrap();
+        let mut found = false;
+        for node in nodes.iter_mut().filter(|node| address.matches(node)) {
+            update(node);
+            found = true;
+        }
+        found
+    }
+
+    fn edit(&self, address: &NodeAddr, edit: &mut dyn FnMut(&mut Node)) -> io::Result<Option<Node>> {
+        let mut nodes = self.nodes.lock().unwrap();
+        Ok(nodes.iter_mut().find(|node| address.matches(node)).map(|node| {
+            edit(node);
+            node.clone()
+        }))
+    }
+
+    fn sample(&self, count: usize, filter: &dyn Fn(&Node) -> bool) -> Vec<Node> {
+        let nodes = self.nodes.lock().unwrap();
+        let candidates: Vec<&Node> = nodes.iter().filter(|node| filter(node)).collect();
+        candidates
+            .choose_multiple(&mut rand::thread_rng(), count)
+            .map(|node| (*node).clone())
+            .collect()
+    }
+
+    fn list(&self) -> Vec<Node> {
+        self.nodes.lock().unwrap().clone()
+    }
+
+    fn expire(&self, now: u64, max_age: u64) -> io::Result<Vec<Node>> {
+        let mut nodes = self.nodes.lock().unwrap();
+        Ok(deactivate_where(&mut nodes, now, |node| !node.is_alive(now, max_age)))
+    }
+
+    fn purge(&self, now: u64, retention: u64) -> io::Result<Vec<Node>> {
+        let mut nodes = self.nodes.lock().unwrap();
+        Ok(drain_where(&mut nodes, |node| is_purgeable(node, now, retention)))
+    }
+
+    fn remove(&self, filter: &dyn Fn(&Node) -> bool) -> io::Result<Vec<Node>> {
+        let mut nodes = self.nodes.lock().unwrap();
+        Ok(drain_where(&mut nodes, filter))
+    }
+}
+
+/// a store keeping the nodes in memory and logging every change to disk
+pub struct FileStore {
+    memory: MemoryStore,
+    /// always lock `memory.nodes` before `log`, so that the log is written in the same order
+    log: Mutex<NodeLog>,
+}
+
+impl FileStore {
+    /// open the storage in `dir` and load the nodes stored in it,
+    /// the loaded nodes get a fresh TTL as if they just sent a heartbeat at `now`,
+    /// the inactive ones are considered last seen when they became inactive
+    pub fn open(dir: &Path, now: u64) -> io::Result<FileStore> {
+        let (log, mut nodes) = NodeLog::open(dir)?;
+        for node in nodes.iter_mut() {
+            node.last_seen = node.inactive_since.unwrap_or(now);
+        }
+        Ok(FileStore {
+            memory: MemoryStore::new(nodes),
+            log: Mutex::new(log),
+        })
+    }
+}
+
+impl NodeStore for FileStore {
+    fn insert_if(&self, node: Node, accept: &mut dyn FnMut(&[Node]) -> bool) -> io::Result<Option<bool>> {
+        let mut nodes = self.memory.nodes.lock().unwrap();
+        if !accept(&nodes) {
+            return Ok(None);
+        }
+        self.log.lock().unwrap().append(Op::Register { node: node.clone() })?;
+        Ok(Some(upsert(&mut nodes, node)))
+    }
+
+    fn deactivate(&self, address: &NodeAddr, now: u64) -> io::Result<Vec<Node>> {
+        let mut nodes = self.memory.nodes.lock().unwrap();
+        if !nodes.iter().any(|node| address.matches(node) && node.inactive_since.is_none()) {
+            return Ok(Vec::new());
+        }
+        self.log.lock().unwrap().append(Op::Deactivate {
+            address: address.clone(),
+            inactive_since: now,
+        })?;
+        Ok(deactivate_where(&mut nodes, now, |node| address.matches(node)))
+    }
+
+    fn find(&self, address: &NodeAddr) -> Vec<Node> {
+        self.memory.find(address)
+    }
+
+    fn update(&self, address: &NodeAddr, update: &mut dyn FnMut(&mut Node)) -> bool {
+        self.memory.update(address, update)
+    }
+
+    fn edit(&self, address: &NodeAddr, edit: &mut dyn FnMut(&mut Node)) -> io::Result<Option<Node>> {
+        let mut nodes = self.memory.nodes.lock().unwrap();
+        let node = match nodes.iter_mut().find(|node| address.matches(node)) {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        // the edited node replaces the stored one on replay, as a registration does
+        let mut edited = node.clone();
+        edit(&mut edited);
+        self.log.lock().unwrap().append(Op::Register { node: edited.clone() })?;
+        *node = edited.clone();
+        Ok(Some(edited))
+    }
+
+    fn sample(&self, count: usize, filter: &dyn Fn(&Node) -> bool) -> Vec<Node> {
+        self.memory.sample(count, filter)
+    }
+
+    fn list(&self) -> Vec<Node> {
+        self.memory.list()
+    }
+
+    fn expire(&self, now: u64, max_age: u64) -> io::Result<Vec<Node>> {
+        let mut nodes = self.memory.nodes.lock().unwrap();
+        let expired = deactivate_where(&mut nodes, now, |node| !node.is_alive(now, max_age));
+        let mut log = self.log.lock().unwrap();
+        for node in &expired {
+            // the log is compacted from the in-memory list, so a failure here
+            // only means the node is loaded again (with a fresh TTL) after a crash
+            let op = Op::Deactivate { address: node.addr(), inactive_since: now };
+            if let Err(err) = log.append(op) {
+                tracing::error!(node = %node.addr(), error = %err, "failed to persist the deactivation");
+            }
+        }
+        Ok(expired)
+    }
+
+    fn purge(&self, now: u64, retention: u64) -> io::Result<Vec<Node>> {
+        let mut nodes = self.memory.nodes.lock().unwrap();
+        let purged = drain_where(&mut nodes, |node| is_purgeable(node, now, retention));
+        let mut log = self.log.lock().unwrap();
+        for node in &purged {
+            // as for the deactivations, a failure only means the node is loaded again
+            if let Err(err) = log.append(Op::Deregister { address: node.addr() }) {
+                tracing::error!(node = %node.addr(), error = %err, "failed to persist the purge");
+            }
+        }
+        Ok(purged)
+    }
+
+    fn remove(&self, filter: &dyn Fn(&Node) -> bool) -> io::Result<Vec<Node>> {
+        let mut nodes = self.memory.nodes.lock().unwrap();
+        // unlike a purge, a removal is written before it is answered
+        let mut log = self.log.lock().unwrap();
+        for node in nodes.iter().filter(|node| filter(node)) {
+            log.append(Op::Deregister { address: node.addr() })?;
+        }
+        Ok(drain_where(&mut nodes, filter))
+    }
+
+    fn compact(&self) -> io::Result<()> {
+        let nodes = self.memory.nodes.lock().unwrap();
+        let mut log = self.log.lock().unwrap();
+        if log.entries() == 0 {
+            return Ok(());
+        }
+        log.compact(&nodes)
+    }
+}
+
+/// add `node` to `nodes`, or refresh the node registered at the same port and either address,
+/// return `true` if the node was added (or was inactive)
+/// a dual-stack registration matching two single-stack nodes merges them into one
+pub fn upsert(nodes: &mut Vec<Node>, node: Node) -> bool {
+    let address = node.addr();
+    let mut matching = nodes
+        .iter()
+        .enumerate()
+        .filter(|(_, existing)| address.matches(existing))
+        .map(|(index, _)| index);
+    let first = match matching.next() {
+        Some(first) => first,
+        None => {
+            nodes.push(node);
+            return true;
+        }
+    };
+    let duplicates: Vec<usize> = matching.collect();
+    for index in duplicates.into_iter().rev() {
+        nodes.remove(index);
+    }
+    let reactivated = nodes[first].inactive_since.is_some();
+    nodes[first].refresh(node);
+    reactivated
+}
+
+/// mark the nodes accepted by `filter` inactive at `now`, return those that were not yet
+fn deactivate_where(nodes: &mut [Node], now: u64, filter: impl Fn(&Node) -> bool) -> Vec<Node> {
+    let mut deactivated = Vec::new();
+    for node in nodes.iter_mut().filter(|node| node.inactive_since.is_none() && filter(node)) {
+        node.inactive_since = Some(now);
+        deactivated.push(node.clone());
+    }
+    deactivated
+}
+
+/// whether `node` has been inactive for `retention` seconds at `now`
+fn is_purgeable(node: &Node, now: u64, retention: u64) -> bool {
+    node.inactive_since
+        .is_some_and(|inactive_since| now.saturating_sub(inactive_since) >= retention)
+}
+
+/// remove and return the nodes accepted by `filter`, keeping the order of the others
+fn drain_where(nodes: &mut Vec<Node>, filter: impl Fn(&Node) -> bool) -> Vec<Node> {
+    let (drained, kept): (Vec<Node>, Vec<Node>) = nodes.drain(..).partition(|node| filter(node));
+    *nodes = kept;
+    drained
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::test_node;
+
+    #[test]
+    fn file_store_keeps_its_changes_across_restarts() {
+        let dir = std::env::temp_dir().join(format!("rusty_coin_dns_file_store_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = FileStore::open(&dir, 0).unwrap();
+        for port in 1..=3 {
+            assert!(store.insert_if(test_node("93.184.216.34", port, 10), &mut |_| true).unwrap().unwrap());
+        }
+        assert!(store.insert_if(test_node("93.184.216.34", 4, 950), &mut |_| true).unwrap().unwrap());
+        assert!(!store.insert_if(test_node("93.184.216.34", 1, 20), &mut |_| true).unwrap().unwrap());
+        assert_eq!(store.deactivate(&test_node("93.184.216.34", 2, 0).addr(), 30).unwrap().len(), 1);
+        assert_eq!(store.remove(&|node| node.port == 3).unwrap().len(), 1);
+        store.compact().unwrap();
+        // a change after the compaction is only in the log
+        assert_eq!(store.expire(1000, 100).unwrap().len(), 1);
+        let edited = store.edit(&test_node("93.184.216.34", 4, 0).addr(), &mut |node| node.metadata.best_height = Some(7)).unwrap();
+        assert_eq!(edited.unwrap().metadata.best_height, Some(7));
+        assert!(store.edit(&test_node("93.184.216.34", 5, 0).addr(), &mut |_| {}).unwrap().is_none());
+        drop(store);
+
+        let store = FileStore::open(&dir, 2000).unwrap();
+        let mut nodes: Vec<(u16, Option<u64>, u64)> = store
+            .list()
+            .iter()
+            .map(|node| (node.port, node.inactive_since, node.last_seen))
+            .collect();
+        nodes.sort();
+        // the active nodes get a fresh TTL, the inactive ones were last seen when they became inactive
+        assert_eq!(nodes, [(1, Some(1000), 1000), (2, Some(30), 30), (4, None, 2000)]);
+        assert_eq!(store.find(&test_node("93.184.216.34", 4, 0).addr())[0].metadata.best_height, Some(7));
+        assert_eq!(store.purge(1050, 1000).unwrap().len(), 1);
+        drop(store);
+        assert_eq!(FileStore::open(&dir, 2000).unwrap().list().len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_conditional_inserts_see_each_other() {
+        // every thread accepts its node only while fewer than 3 nodes are stored
+        let store = MemoryStore::new(Vec::new());
+        std::thread::scope(|scope| {
+            for port in 1..=16 {
+                let store = &store;
+                scope.spawn(move || store.insert_if(test_node("93.184.216.34", port, 10), &mut |nodes| nodes.len() < 3).unwrap());
+            }
+        });
+        assert_eq!(store.list().len(), 3);
+    }
+}
@@ -0,0 +1,286 @@
+//! validation of the node addresses and metadata posted by the clients
+//! a rejected request is answered with 400 Bad Request and a JSON body naming the field, e.g.
+//! `{"error": "invalid_field", "field": "ipv6_address", "message": "..."}`, see the `api` module
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use actix_web::{error, HttpRequest};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use crate::api::ApiError;
+use crate::node::{Metadata, NodeAddr};
+
+/// maximum length of a user agent, as in the peer-to-peer `version` message
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// a node address as posted by a client, before it is parsed and validated
+/// the addresses default to the address the request came from
+#[derive(Debug, Deserialize, JsonSchema)]
+#[schemars(example = serde_json::json!({ "ipv4_address": "93.184.216.34", "port": 8333 }))]
+pub struct NodeRequest {
+    #[serde(default)]
+    #[schemars(extend("format" = "ipv4"))]
+    pub ipv4_address: Option<String>,
+    #[serde(default)]
+    #[schemars(extend("format" = "ipv6"))]
+    pub ipv6_address: Option<String>,
+    #[schemars(range(min = 1))]
+    pub port: u16,
+    /// the fields of a signed request, see the `signature` module
+    #[serde(default)]
+    pub public_key: Option<String>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    #[serde(default)]
+    pub nonce: Option<String>,
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// the solved challenge of a new registration, see the `pow` module
+    #[serde(default)]
+    pub pow_challenge: Option<String>,
+    #[serde(default)]
+    pub pow_solution: Option<String>,
+    /// the metadata of the node, see `node::Metadata`,
+    /// only `best_height` is taken from a heartbeat
+    #[serde(default)]
+    pub protocol_version: Option<u32>,
+    #[serde(default)]
+    pub services: Option<u64>,
+    #[serde(default)]
+    #[schemars(length(max = MAX_USER_AGENT_LEN))]
+    pub user_agent: Option<String>,
+    #[serde(default)]
+    pub best_height: Option<u64>,
+}
+
+/// what to do when an address in the body of a request differs from the address
+/// the request came from (see the `client` module)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MismatchPolicy {
+    /// use the addresses in the body, the observed address is only used if the body has none
+    Allow,
+    /// ignore the addresses in the body, only use the observed address
+    Override,
+    /// only use the observed address, reject the request if the body has any other address
+    Reject,
+}
+
+impl FromStr for MismatchPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "allow" => Ok(MismatchPolicy::Allow),
+            "override" => Ok(MismatchPolicy::Override),
+            "reject" => Ok(MismatchPolicy::Reject),
+            _ => Err(format!("{:?} is not one of allow, override, reject", s)),
+        }
+    }
+}
+
+impl fmt::Display for MismatchPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MismatchPolicy::Allow => write!(f, "allow"),
+            MismatchPolicy::Override => write!(f, "override"),
+            MismatchPolicy::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+impl NodeRequest {
+    /// parse the addresses of a node and reconcile them with the address `observed`
+    /// the request came from according to `policy`, without checking whether they may be
+    /// registered, the body may omit the addresses altogether and only specify the port
+    /// with `override` and `reject`, only the observed address is kept, so the other address
+    /// of a dual-stack node (which cannot be verified) is left out, a refresh keeps the address
+    /// registered before (see `Node::refresh`)
+    pub fn resolve(
+        &self,
+        observed: Option<IpAddr>,
+        policy: MismatchPolicy,
+    ) -> Result<NodeAddr, ApiError> {
+        let mut ipv4_address = parse_field::<Ipv4Addr>("ipv4_address", "IPv4", &self.ipv4_address)?;
+        let mut ipv6_address = parse_field::<Ipv6Addr>("ipv6_address", "IPv6", &self.ipv6_address)?;
+
+        if let Some(observed) = observed {
+            if policy == MismatchPolicy::Reject {
+                if let Some(ipv4) = ipv4_address.filter(|ipv4| IpAddr::V4(*ipv4) != observed) {
+                    return Err(mismatch("ipv4_address", IpAddr::V4(ipv4), observed));
+                }
+                if let Some(ipv6) = ipv6_address.filter(|ipv6| IpAddr::V6(*ipv6) != observed) {
+                    return Err(mismatch("ipv6_address", IpAddr::V6(ipv6), observed));
+                }
+            }
+            let use_observed = policy != MismatchPolicy::Allow
+                || (ipv4_address.is_none() && ipv6_address.is_none());
+            if use_observed {
+                (ipv4_address, ipv6_address) = match observed {
+                    IpAddr::V4(ipv4) => (Some(ipv4), None),
+                    IpAddr::V6(ipv6) => (None, Some(ipv6)),
+                };
+            }
+        }
+
+        if ipv4_address.is_none() && ipv6_address.is_none() {
+            return Err(ApiError::invalid_field(
+                "ipv4_address",
+                "either ipv4_address or ipv6_address is required".to_string(),
+            ));
+        }
+        if self.port == 0 {
+            return Err(ApiError::invalid_field("port", "port 0 is not allowed".to_string()));
+        }
+        Ok(NodeAddr { ipv4_address, ipv6_address, port: self.port })
+    }
+}
+
+impl NodeRequest {
+    /// the metadata of a registration, the user agent must be printable ASCII
+    pub fn metadata(&self) -> Result<Metadata, ApiError> {
+        Ok(Metadata {
+            protocol_version: self.protocol_version,
+            services: self.services.unwrap_or_default(),
+            user_agent: self.user_agent.as_deref().map(check_user_agent).transpose()?.flatten(),
+            best_height: self.best_height,
+        })
+    }
+}
+
+/// check the user agent of a node, return it trimmed, `None` if it is empty
+pub fn check_user_agent(user_agent: &str) -> Result<Option<String>, ApiError> {
+    match user_agent.trim() {
+        user_agent if user_agent.len() > MAX_USER_AGENT_LEN => Err(ApiError::invalid_field(
+            "user_agent",
+            format!("the user agent must be at most {} characters", MAX_USER_AGENT_LEN),
+        )),
+        user_agent if !user_agent.bytes().all(|byte| byte.is_ascii_graphic() || byte == b' ') => Err(
+            ApiError::invalid_field("user_agent", "the user agent must be printable ASCII".to_string()),
+        ),
+        "" => Ok(None),
+        user_agent => Ok(Some(user_agent.to_string())),
+    }
+}
+
+/// check that the addresses of a node may be registered,
+/// i.e. that they are publicly routable addresses unless `allow_private` is set
+pub fn check_addr(address: &NodeAddr, allow_private: bool) -> Result<(), ApiError> {
+    if let Some(ipv4) = address.ipv4_address {
+        check_ip(IpAddr::V4(ipv4), allow_private)
+            .map_err(|message| ApiError::invalid_field("ipv4_address", message))?;
+    }
+    if let Some(ipv6) = address.ipv6_address {
+        check_ip(IpAddr::V6(ipv6), allow_private)
+            .map_err(|message| ApiError::invalid_field("ipv6_address", message))?;
+    }
+    Ok(())
+}
+
+fn mismatch(field: &'static str, posted: IpAddr, observed: IpAddr) -> ApiError {
+    ApiError::invalid_field(
+        field,
+        format!("{} does not match the address the request came from ({})", posted, observed),
+    )
+}
+
+/// parse an optional address field
+fn parse_field<T: FromStr>(
+    field: &'static str,
+    family: &str,
+    value: &Option<String>,
+) -> Result<Option<T>, ApiError> {
+    match value {
+        Some(value) => value.trim().parse::<T>().map(Some).map_err(|_| {
+            ApiError::invalid_field(field, format!("{:?} is not an {} address", value, family))
+        }),
+        None => Ok(None),
+    }
+}
+
+/// check that `ip` is a publicly routable unicast address
+/// loopback, private, shared (carrier-grade NAT), link-local, multicast, documentation,
+/// benchmarking and reserved addresses are only accepted
+/// if `allow_private` is set, unspecified and broadcast addresses are never accepted
+pub fn check_ip(ip: IpAddr, allow_private: bool) -> Result<(), String> {
+    if ip.is_unspecified() {
+        return Err(format!("{} is the unspecified address", ip));
+    }
+    if ip == IpAddr::V4(Ipv4Addr::BROADCAST) {
+        return Err(format!("{} is the broadcast address", ip));
+    }
+    if allow_private {
+        return Ok(());
+    }
+    let range = match ip {
+        IpAddr::V4(ip) => ipv4_range(ip),
+        IpAddr::V6(ip) => ipv6_range(ip),
+    };
+    match range {
+        Some(range) => Err(format!(
+            "{} is a {} address, which is only allowed in private network mode",
+            ip, range
+        )),
+        None => Ok(()),
+    }
+}
+
+/// the name of the non-public range `ip` belongs to
+fn ipv4_range(ip: Ipv4Addr) -> Option<&'static str> {
+    let octets = ip.octets();
+    if ip.is_loopback() {
+        Some("loopback")
+    } else if ip.is_private() {
+        Some("private")
+    } else if octets[0] == 100 && octets[1] & 0xc0 == 64 {
+        // 100.64.0.0/10
+        Some("shared (carrier-grade NAT)")
+    } else if ip.is_link_local() {
+        Some("link-local")
+    } else if ip.is_multicast() {
+        Some("multicast")
+    } else if ip.is_documentation() {
+        Some("documentation")
+    } else if octets[0] == 198 && octets[1] & 0xfe == 18 {
+        // 198.18.0.0/15
+        Some("benchmarking")
+    } else if octets[0] & 0xf0 == 240 || octets[..3] == [192, 0, 0] {
+        // 240.0.0.0/4 and the IETF protocol assignments 192.0.0.0/24
+        Some("reserved")
+    } else {
+        None
+    }
+}
+
+/// the name of the non-public range `ip` belongs to
+fn ipv6_range(ip: Ipv6Addr) -> Option<&'static str> {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return ipv4_range(ip);
+    }
+    let segments = ip.segments();
+    if ip.is_loopback() {
+        Some("loopback")
+    } else if ip.is_unique_local() {
+        Some("private")
+    } else if ip.is_unicast_link_local() {
+        Some("link-local")
+    } else if ip.is_multicast() {
+        Some("multicast")
+    } else if segments[0] == 0x2001 && segments[1] == 0x0db8 {
+        Some("documentation")
+    } else {
+        None
+    }
+}
+
+/// answer a JSON body that cannot be deserialized (e.g. a missing field or a port out of range)
+/// with the same structured 400 as a field that failed validation
+pub fn json_error_handler(err: error::JsonPayloadError, _req: &HttpRequest) -> error::Error {
+    ApiError::InvalidBody(err.to_string()).into()
+}
+
+/// answer a query string that cannot be deserialized (e.g. an unknown address family)
+/// with the same structured 400 as a field that failed validation
+pub fn query_error_handler(err: error::QueryPayloadError, _req: &HttpRequest) -> error::Error {
+    ApiError::InvalidQuery(err.to_string()).into()
+}